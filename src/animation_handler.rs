@@ -1,216 +1,409 @@
 use avian3d::prelude::LinearVelocity;
 use bevy::prelude::*;
 use bevy_tnua::prelude::TnuaController;
-use std::time::Duration;
+use std::collections::HashMap;
 
-use crate::{asset_loader::{AssetLoadingState, CharacterHandle, EnemyHandle, MyGameHandle}, combat_manager::{AttackType, CombatAction}, AnimationEntityLink};
+use crate::{asset_loader::{AnimatedModel, AnimatedModelSources, AnimatedModels, AssetLoadingState}, combat_manager::{AttackBodyMask, CombatAction}, AnimationEntityLink};
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum ResourceHandle {
-    Character,
-    Enemy
-}
+pub mod blending;
+pub mod debug_panel;
+pub mod foot_ik;
+pub mod look_at;
+pub mod notify;
+pub mod root_motion;
+pub mod state_machine;
+
+use blending::AnimationBlender;
+use debug_panel::ForcedAnimation;
+use notify::AnimationNotifyTracker;
+use root_motion::RootMotion;
+use state_machine::{AnimationContext, AnimationStateDefinition, AnimationStateMachine};
 
 #[derive(Component)]
+#[require(AnimationNotifyTracker, RootMotion)]
 pub struct AnimationHandler {
     pub current_animation: usize,
-    pub resource_type: ResourceHandle
+    // State in the model's `AnimationStateMachine`, `None` until the first evaluation.
+    pub current_state: Option<String>,
+    // State playing on top of `current_state` on the upper body, during upper-body attacks.
+    pub upper_body_state: Option<String>,
+    // Id of the `AnimatedModel` the entity's scene was spawned from.
+    pub model: String
+}
+
+// Plays other clips in place of the regular ones, keyed by the regular animation name. Clips
+// missing from the model fall back to the regular animation.
+#[derive(Component, Debug, Clone, Default)]
+pub struct AnimationSet(pub HashMap<String, String>);
+
+impl AnimationSet {
+    fn animation_index(animation_set: Option<&Self>, model: &AnimatedModel, name: &str) -> Option<usize> {
+        animation_set
+            .and_then(|animation_set| animation_set.0.get(name))
+            .and_then(|replacement| model.get_animation_name_reference(replacement))
+            .or_else(|| model.get_animation_name_reference(name))
+            .copied()
+    }
 }
 
 pub fn plugin(app: &mut App) {
     app
+    .add_plugins((blending::plugin, debug_panel::plugin, foot_ik::plugin, look_at::plugin, notify::plugin, root_motion::plugin, state_machine::plugin))
     .add_systems(Update, (
-        add_animation_transition_to_player::<CharacterHandle>,
-        add_animation_transition_to_player::<EnemyHandle>,
-        animation_handler
+        add_animation_transition_to_player,
+        animation_handler,
+        report_missing_animation_clips
     ).run_if(in_state(AssetLoadingState::Loaded)));
 }
 
 
-fn add_animation_transition_to_player<T: Resource + MyGameHandle>(
+fn add_animation_transition_to_player(
     mut commands: Commands,
-    mut players: Query<(Entity, &mut AnimationPlayer)>,
-    anim_link_query: Query<(&AnimationHandler, &AnimationEntityLink), Added<AnimationEntityLink>>,
-    character_handle: Res<T>,
+    mut players: Query<&mut AnimationPlayer, Without<AnimationGraphHandle>>,
+    anim_link_query: Query<(&AnimationHandler, Ref<AnimationEntityLink>)>,
+    animated_models: Res<AnimatedModels>,
+    model_sources: Res<AnimatedModelSources>,
 ) {
     for (anim_handler, anim_link) in anim_link_query.iter() {
+        // Players linked earlier already have their graph.
+        for linked_player in anim_link.0.iter() {
+            let model_id = linked_player.model.as_ref().unwrap_or(&anim_handler.model);
 
-        if anim_handler.resource_type != character_handle.get_resource_type() {
-            continue;
+            // Attachment models may still be loading, the player gets its graph once they're in.
+            let Some(model) = animated_models.get(model_id) else {
+                if anim_link.is_changed() && !model_sources.contains(model_id) {
+                    warn!("No animated model {}, its entities won't animate", model_id);
+                }
+                continue;
+            };
+
+            let Ok(mut player) = players.get_mut(linked_player.entity) else {
+                continue;
+            };
+
+            let mut blender = AnimationBlender::default();
+
+            // The state machine takes over on the next frame, this just avoids a frame of bind pose.
+            if let Some(first_animation) = model.get_animations(0) {
+                player.play(*first_animation).repeat();
+                blender.blend_to([(*first_animation, 1.)], 0.);
+            }
+
+            commands
+                .entity(linked_player.entity)
+                .insert(AnimationGraphHandle(model.animation_graph.clone()))
+                .insert(blender);
         }
-        
-        let Ok((entity, mut player)) = players.get_mut(anim_link.0) else {
+    }
+}
+
+
+// Which copy of a clip in the animation graph to play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyLayer {
+    Full,
+    Upper,
+    Lower
+}
+
+fn layer_node(model: &AnimatedModel, animation_index: usize, layer: BodyLayer) -> Option<AnimationNodeIndex> {
+    match layer {
+        BodyLayer::Full => model.get_animations(animation_index).copied(),
+        BodyLayer::Upper => model.get_body_layer_animations(animation_index).map(|(upper_body, _)| *upper_body),
+        BodyLayer::Lower => model.get_body_layer_animations(animation_index).map(|(_, lower_body)| *lower_body),
+    }
+}
+
+fn clip_duration(graph: Option<&AnimationGraph>, clips: &Assets<AnimationClip>, animation_node: AnimationNodeIndex) -> Option<f32> {
+    match &graph?.get(animation_node)?.node_type {
+        AnimationNodeType::Clip(clip) => clips.get(clip).map(AnimationClip::duration),
+        _ => None
+    }
+}
+
+// The clips of a state with their weights, from its blend space or else from its clip and fallbacks.
+// Empty when the model has none of them, `report_missing_animation_clips` already told about it.
+fn state_clips(state: &AnimationStateDefinition, context: &AnimationContext, animation_set: Option<&AnimationSet>, model: &AnimatedModel) -> Vec<(usize, f32)> {
+    let clip_index = |clip: &str| AnimationSet::animation_index(animation_set, model, clip);
+
+    let mut clips: Vec<(usize, f32)> = state
+        .blend_space
+        .iter()
+        .flat_map(|blend_space| blend_space.weights(context, |clip| clip_index(clip).is_some()))
+        .filter_map(|(clip, weight)| clip_index(clip).map(|animation_index| (animation_index, weight)))
+        .collect();
+
+    if clips.is_empty() {
+        clips.extend(state.clips().find_map(clip_index).map(|animation_index| (animation_index, 1.)));
+    }
+
+    clips
+}
+
+// Makes sure the clips play on the given layer and returns them as blend targets.
+fn play_state_clips(
+    anim_player: &mut AnimationPlayer,
+    model: &AnimatedModel,
+    state: &AnimationStateDefinition,
+    clips: &[(usize, f32)],
+    layer: BodyLayer,
+    entered: bool,
+    speed: impl Fn(AnimationNodeIndex) -> f32,
+) -> Vec<(AnimationNodeIndex, f32)> {
+    let mut targets = Vec::new();
+
+    for (animation_index, weight) in clips {
+        let Some(animation_node) = layer_node(model, *animation_index, layer) else {
             continue;
         };
 
-        println!("THIS IS IN ANIM TRANSITION: {:?}, {:?}, {:?}", character_handle.get_animation_name_reference("Idle"), anim_link.0, anim_link);
+        // One-shot clips start over every time their state is entered, looping ones carry on.
+        let restart = entered && !state.looping;
 
+        // Switching layers, e.g. the legs moving to the lower body when an upper-body attack
+        // starts, carries on from where the clip's other copy is instead of jumping to its start.
+        let carried_seek_time = (!restart && anim_player.animation(animation_node).is_none())
+            .then(|| {
+                [BodyLayer::Full, BodyLayer::Upper, BodyLayer::Lower]
+                    .into_iter()
+                    .filter_map(|other_layer| layer_node(model, *animation_index, other_layer))
+                    .filter_map(|other_node| anim_player.animation(other_node))
+                    .max_by(|a, b| a.weight().total_cmp(&b.weight()))
+                    .map(|active_animation| active_animation.seek_time())
+            })
+            .flatten();
 
-        let mut transitions = AnimationTransitions::new();
+        let active_animation = if restart {
+            anim_player.start(animation_node)
+        } else {
+            anim_player.play(animation_node)
+        };
 
-        transitions
-            .play(&mut player, *character_handle.get_animations(0), Duration::ZERO)
-            .repeat();
+        if let Some(seek_time) = carried_seek_time {
+            active_animation.seek_to(seek_time);
+        }
+
+        active_animation.set_speed(speed(animation_node));
+
+        if state.looping {
+            active_animation.repeat();
+        }
 
-        commands
-            .entity(entity)
-            .insert(AnimationGraphHandle(character_handle.get_animation_graph().clone()))
-            .insert(transitions);
+        targets.push((animation_node, *weight));
     }
+
+    targets
 }
 
+type AnimatedSceneQueryData<'a> = (
+    &'a Transform,
+    &'a LinearVelocity,
+    Option<&'a TnuaController>,
+    Option<&'a CombatAction>,
+    Option<&'a AnimationSet>,
+    &'a mut AnimationHandler,
+    &'a AnimationEntityLink,
+);
 
+// Runs the model's animation state machine for every animated entity and plays the result on
+// each of its animation players. Upper-body attacks run a second pass of the state machine for
+// the upper body, while the first pass ignores the attack and keeps the legs moving.
 fn animation_handler(
-    mut animation_players: Query<(&mut AnimationPlayer, &mut AnimationTransitions)>,
-    character_handle: Res<CharacterHandle>,
-    enemy_handle: Res<EnemyHandle>,
-    mut animated_scene_query: Query<(&LinearVelocity, Option<&TnuaController>, Option<&CombatAction>, &mut AnimationHandler, &AnimationEntityLink), With<AnimationHandler>>
+    mut animation_players: Query<(&mut AnimationPlayer, &mut AnimationBlender)>,
+    animated_models: Res<AnimatedModels>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    graphs: Res<Assets<AnimationGraph>>,
+    clips: Res<Assets<AnimationClip>>,
+    mut animated_scene_query: Query<AnimatedSceneQueryData, Without<ForcedAnimation>>
 ) {
-    let mut count = 0;
+    for (transform, velocity, tnua_context_option, combat_action_option, animation_set, mut animation_handler, animation_entity_link) in animated_scene_query.iter_mut() {
+        let Some(model) = animated_models.get(&animation_handler.model) else {
+            continue;
+        };
 
+        let Some(state_machine) = model.get_state_machine(&state_machines) else {
+            continue;
+        };
 
-    for (velocity, tnua_context_option, combat_action_option, mut animation_handler, animation_entity_link) in animated_scene_query.iter_mut() {
+        let local_velocity = transform.rotation.inverse() * velocity.0;
 
+        let context = AnimationContext {
+            speed: velocity.xz().length(),
+            local_velocity: local_velocity.xz(),
+            airborne: tnua_context_option.is_some_and(|tnua_context| tnua_context.is_airborne().unwrap_or(false)),
+            attack: combat_action_option.map(|combat_action| combat_action.attack_type),
+        };
 
-        //println!("anim link: {:?}", animation_entity_link.0);
+        // Models that can't be split play every attack on the full body.
+        let upper_body_attack = combat_action_option.is_some_and(|combat_action| combat_action.body_mask == AttackBodyMask::UpperBody)
+            && model.get_body_layer_animations(0).is_some();
 
-        let Ok((mut anim_player, mut transitions)) = animation_players.get_mut(animation_entity_link.0) else {
-            return;
+        let base_context = if upper_body_attack {
+            AnimationContext { attack: None, ..context }
+        } else {
+            context
         };
 
-        let is_player: bool;
+        let (base_state, base_blend) = state_machine.next_state(animation_handler.current_state.as_deref(), &base_context);
+        let base_state = base_state.to_string();
 
-        match tnua_context_option {
-            None => is_player = false,
-            Some(tnua_context) => {
-                is_player = true;
-            }
+        let Some(state) = state_machine.states.get(&base_state) else {
+            continue;
+        };
+
+        let base_clips = state_clips(state, &base_context, animation_set, model);
+
+        if base_clips.is_empty() {
+            continue;
         }
 
-        
-        //println!("is player: {:?}", is_player);
+        let base_entered = animation_handler.current_state.as_ref() != Some(&base_state);
+        let base_layer = if upper_body_attack { BodyLayer::Lower } else { BodyLayer::Full };
 
-        if is_player {
-            let tnua_context = tnua_context_option.unwrap();
-            let Ok(is_airborne) = tnua_context.is_airborne() else {
-                println!("Failed to check if tnua_context is airborne");
+        let upper_body = upper_body_attack
+            .then(|| {
+                let current_upper_body_state = animation_handler.upper_body_state.as_deref().or(Some(base_state.as_str()));
+                state_machine.next_state(current_upper_body_state, &context)
+            })
+            .and_then(|(upper_body_state, upper_body_blend)| {
+                state_machine.states.get(upper_body_state).map(|state| (upper_body_state.to_string(), state, upper_body_blend))
+            });
+
+        let upper_body_state = upper_body.as_ref().map(|(upper_body_state, _, _)| upper_body_state.clone());
+        let upper_body_entered = upper_body_state.is_some() && animation_handler.upper_body_state != upper_body_state;
+        let blend = match &upper_body {
+            Some((_, _, upper_body_blend)) if upper_body_entered => *upper_body_blend,
+            _ => base_blend
+        };
+        let changed = base_entered || animation_handler.upper_body_state != upper_body_state;
+
+        let mut dominant_clips = base_clips;
+
+        for linked_player in animation_entity_link.0.iter() {
+            // Attachments play the clips of the same name from their own model, if they have them.
+            let Some(player_model) = linked_player.model.as_deref().map_or(Some(model), |attachment_model| animated_models.get(attachment_model)) else {
                 continue;
             };
-    
-            if combat_action_option.is_some() {
-                if combat_action_option.unwrap().attack_type == AttackType::Light {
-                    if animation_handler.current_animation != *character_handle.animation_name_reference.get("LightAttack").unwrap() {
-                        animation_handler.current_animation = *character_handle.animation_name_reference.get("LightAttack").unwrap();
-                        transitions
-                        .play(
-                            &mut anim_player,
-                            character_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(50),
-                        )
-                        .set_speed(2.);
-                    } 
-                } else {
-                    if animation_handler.current_animation != *character_handle.animation_name_reference.get("HeavyAttack").unwrap() {
-                        animation_handler.current_animation = *character_handle.animation_name_reference.get("HeavyAttack").unwrap();
-                        transitions
-                        .play(
-                            &mut anim_player,
-                            character_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(50),
-                        )
-                        .set_speed(2.);
-                    }
-                }
-            } else {
-                if is_airborne {
-                    if animation_handler.current_animation != *character_handle.animation_name_reference.get("Jumping").unwrap() {
-                        animation_handler.current_animation = *character_handle.animation_name_reference.get("Jumping").unwrap();
-                        transitions
-                        .play(
-                            &mut anim_player,
-                            character_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(50),
-                        )
-                        .set_speed(0.8);
-                    }
-                } else if velocity.length() > 0.25 && !is_airborne {
-                    if animation_handler.current_animation != *character_handle.animation_name_reference.get("Running").unwrap() {
-                        animation_handler.current_animation = *character_handle.animation_name_reference.get("Running").unwrap();
-                        transitions
-                        .play(
-                            &mut anim_player,
-                            character_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(250),
-                        )
-                        .repeat();
-                    }
-                } else {
-                    if animation_handler.current_animation != *character_handle.animation_name_reference.get("Idle").unwrap() {
-                        animation_handler.current_animation = *character_handle.animation_name_reference.get("Idle").unwrap();
-                        println!("{}", animation_handler.current_animation);
-                    transitions
-                        .play(
-                            &mut anim_player,
-                            character_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(250),
-                        )
-                        .repeat();
-                    }
-                }
+
+            let Ok((mut anim_player, mut blender)) = animation_players.get_mut(linked_player.entity) else {
+                continue;
+            };
+
+            let graph = graphs.get(&player_model.animation_graph);
+
+            let clip_speed = |state: &AnimationStateDefinition, animation_node: AnimationNodeIndex| {
+                state.clip_speed(clip_duration(graph, &clips, animation_node), combat_action_option)
+            };
+
+            let mut targets = Vec::new();
+
+            // Attachments that can't be split play the upper body attack on the whole model.
+            let split = player_model.get_body_layer_animations(0).is_some();
+
+            if split || upper_body.is_none() {
+                let player_base_clips = state_clips(state, &base_context, animation_set, player_model);
+                targets.extend(play_state_clips(&mut anim_player, player_model, state, &player_base_clips, base_layer, base_entered, |animation_node| clip_speed(state, animation_node)));
             }
-        } else {
 
-            if combat_action_option.is_some() {
-                if combat_action_option.unwrap().attack_type == AttackType::Light {
-                    if animation_handler.current_animation != *enemy_handle.animation_name_reference.get("LightAttack").unwrap() {
-                        animation_handler.current_animation = *enemy_handle.animation_name_reference.get("LightAttack").unwrap();
-                        transitions
-                        .play(
-                            &mut anim_player,
-                            enemy_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(50),
-                        ).set_speed(2.);
-                    } 
-                } else {
-                    if animation_handler.current_animation != *enemy_handle.animation_name_reference.get("HeavyAttack").unwrap() {
-                        animation_handler.current_animation = *enemy_handle.animation_name_reference.get("HeavyAttack").unwrap();
-                        transitions
-                        .play(
-                            &mut anim_player,
-                            enemy_handle.animations[animation_handler.current_animation],
-                            Duration::from_millis(50),
-                        )
-                        .set_speed(2.);
-                    }
+            if let Some((_, upper_body_definition, _)) = &upper_body {
+                let upper_body_layer = if split { BodyLayer::Upper } else { BodyLayer::Full };
+                let upper_body_clips = state_clips(upper_body_definition, &context, animation_set, player_model);
+                targets.extend(play_state_clips(&mut anim_player, player_model, upper_body_definition, &upper_body_clips, upper_body_layer, upper_body_entered, |animation_node| clip_speed(upper_body_definition, animation_node)));
+
+                if linked_player.model.is_none() && !upper_body_clips.is_empty() {
+                    dominant_clips = upper_body_clips;
                 }
+            }
+
+            if changed {
+                blender.blend_to(targets, blend);
             } else {
-                if animation_handler.current_animation != *enemy_handle.animation_name_reference.get("Idle").unwrap() {
-                    animation_handler.current_animation = *enemy_handle.animation_name_reference.get("Idle").unwrap();
-                    println!("{}", animation_handler.current_animation);
-                transitions
-                    .play(
-                        &mut anim_player,
-                        enemy_handle.animations[animation_handler.current_animation],
-                        Duration::from_millis(250),
-                    )
-                    .repeat();
-                }
+                blender.set_targets(targets);
             }
+        }
 
-            
+        if let Some((animation_index, _)) = dominant_clips.iter().max_by(|a, b| a.1.total_cmp(&b.1)) {
+            animation_handler.current_animation = *animation_index;
         }
+
+        animation_handler.current_state = Some(base_state);
+        animation_handler.upper_body_state = upper_body_state;
     }
+}
+
+// Lists the clips each model's state machine asks for but the model doesn't have, once both are loaded.
+fn report_missing_animation_clips(
+    animated_models: Res<AnimatedModels>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    mut reported: Local<bool>,
+) {
+    if *reported {
+        return;
+    }
+
+    *reported = true;
 
+    let mut model_ids: Vec<&String> = animated_models.0.keys().collect();
+    model_ids.sort();
 
-    // for (mut anim_player, mut transitions, animation_entity_link) in &mut animation_players {
-    //     println!("HERTE: {:?}, {}", transitions.get_main_animation(), count);
-    //     count+=1;
-    //     println!("anim link: {:?}", animation_entity_link.0);
-    //     let Ok((velocity, tnua_context_option, combat_action_option, mut animation_handler)) = player_query.get_mut(animation_entity_link.0) else {
-    //         continue;
-    //     };
+    for model_id in model_ids {
+        let model = &animated_models.0[model_id];
 
-        
-    // }
+        // Attachments play their character's states, they have nothing to report.
+        if model.state_machine.is_none() {
+            continue;
+        }
+
+        let Some(state_machine) = model.get_state_machine(&state_machines) else {
+            warn!("{} model: animation state machine failed to load", model_id);
+            continue;
+        };
+
+        report_missing_model_clips(model_id, model, state_machine);
+    }
 }
 
+fn report_missing_model_clips(model_id: &str, model: &AnimatedModel, state_machine: &AnimationStateMachine) {
+    let has_clip = |clip: &str| model.get_animation_name_reference(clip).is_some();
+
+    let mut state_names: Vec<&String> = state_machine.states.keys().collect();
+    state_names.sort();
+
+    let mut missing_count = 0;
+
+    for state_name in state_names {
+        let state = &state_machine.states[state_name];
+
+        // Blend spaces just blend whatever clips exist, `clip` is only needed when none do.
+        if let Some(blend_space) = &state.blend_space {
+            let blend_clips = blend_space.clips();
+            let missing: Vec<&str> = blend_clips.iter().copied().filter(|clip| !has_clip(clip)).collect();
+
+            if missing.is_empty() {
+                continue;
+            }
+
+            missing_count += 1;
+            warn!("{} model: blend space of state {} is missing clips {:?}, blending without them", model_id, state_name, missing);
+
+            if missing.len() < blend_clips.len() {
+                continue;
+            }
+        } else if has_clip(&state.clip) {
+            continue;
+        } else {
+            missing_count += 1;
+        }
+
+        match state.clips().find(|clip| has_clip(clip)) {
+            Some(fallback) => warn!("{} model: state {} is missing clip {}, falling back to {}", model_id, state_name, state.clip, fallback),
+            None => warn!("{} model: state {} has no playable clip (tried {:?}), it will be skipped", model_id, state_name, state.clips().collect::<Vec<_>>())
+        }
+    }
+
+    if missing_count == 0 {
+        info!("{} model: all animation clips present", model_id);
+    } else {
+        info!("{} model: {} animation state(s) missing their clip", model_id, missing_count);
+    }
+}
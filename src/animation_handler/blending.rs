@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(PostUpdate, (
+            update_animation_blenders
+        ).before(bevy::app::Animation));
+}
+
+// Cross-fades the clips of an `AnimationPlayer` towards a set of target weights. Unlike
+// `AnimationTransitions` it can keep several clips playing at once, e.g. for blend spaces.
+#[derive(Component, Debug, Default)]
+pub struct AnimationBlender {
+    weights: HashMap<AnimationNodeIndex, f32>,
+    targets: HashMap<AnimationNodeIndex, f32>,
+    // Seconds to go from one set of targets to the next.
+    blend_duration: f32,
+}
+
+impl AnimationBlender {
+    // Fades towards `targets` over `blend_duration`, anything not in there fades out.
+    pub fn blend_to(&mut self, targets: impl IntoIterator<Item = (AnimationNodeIndex, f32)>, blend_duration: f32) {
+        self.blend_duration = blend_duration;
+        self.set_targets(targets);
+    }
+
+    // Changes the targets without changing the blend duration, e.g. a blend space following
+    // the entity's speed.
+    pub fn set_targets(&mut self, targets: impl IntoIterator<Item = (AnimationNodeIndex, f32)>) {
+        self.targets = targets.into_iter().collect();
+    }
+
+    pub fn weights(&self) -> &HashMap<AnimationNodeIndex, f32> {
+        &self.weights
+    }
+
+    pub fn targets(&self) -> &HashMap<AnimationNodeIndex, f32> {
+        &self.targets
+    }
+
+    pub fn blend_duration(&self) -> f32 {
+        self.blend_duration
+    }
+}
+
+fn update_animation_blenders(
+    mut blender_query: Query<(&mut AnimationBlender, &mut AnimationPlayer)>,
+    time: Res<Time>,
+) {
+    for (mut blender, mut player) in blender_query.iter_mut() {
+        let step = if blender.blend_duration > 0. {
+            time.delta_secs() / blender.blend_duration
+        } else {
+            1.
+        };
+
+        let AnimationBlender { weights, targets, .. } = &mut *blender;
+
+        for node in targets.keys() {
+            weights.entry(*node).or_insert(0.);
+        }
+
+        weights.retain(|node, weight| {
+            let target = targets.get(node).copied().unwrap_or(0.);
+            *weight = if *weight < target {
+                (*weight + step).min(target)
+            } else {
+                (*weight - step).max(target)
+            };
+
+            // Faded out completely, nothing to keep playing.
+            if *weight <= 0. && !targets.contains_key(node) {
+                player.stop(*node);
+                return false;
+            }
+
+            if let Some(active_animation) = player.animation_mut(*node) {
+                active_animation.set_weight(*weight);
+            }
+
+            true
+        });
+    }
+}
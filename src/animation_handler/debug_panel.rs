@@ -0,0 +1,179 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::{
+    asset_loader::{AnimatedModel, AnimatedModels, AssetLoadingState},
+    AnimationEntityLink
+};
+
+use super::{blending::AnimationBlender, AnimationHandler};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            display_animation_panel
+        ).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// A clip played from the debug panel, the state machine leaves the entity alone while it's there.
+#[derive(Component, Debug)]
+pub struct ForcedAnimation(pub String);
+
+enum PanelAction {
+    Force(Entity, String),
+    Release(Entity),
+}
+
+type PanelEntityQueryData<'a> = (Entity, Option<&'a Name>, &'a mut AnimationHandler, &'a AnimationEntityLink, Option<&'a ForcedAnimation>);
+
+fn display_animation_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    animated_models: Res<AnimatedModels>,
+    mut animation_players: Query<(&mut AnimationPlayer, &mut AnimationBlender)>,
+    mut animated_query: Query<PanelEntityQueryData>,
+) {
+    let mut actions = Vec::new();
+
+    egui::Window::new("Animations").default_open(false).show(contexts.ctx_mut(), |ui| {
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for (entity, name, animation_handler, animation_entity_link, forced_animation) in animated_query.iter() {
+                let Some(model) = animated_models.get(&animation_handler.model) else {
+                    continue;
+                };
+
+                let title = name.map_or_else(|| format!("{:?}", entity), |name| format!("{} ({:?})", name, entity));
+
+                egui::CollapsingHeader::new(title).id_salt(entity).show(ui, |ui| {
+                    ui.label(format!("Model: {}", animation_handler.model));
+                    ui.label(format!(
+                        "State: {} / upper body: {}",
+                        animation_handler.current_state.as_deref().unwrap_or("-"),
+                        animation_handler.upper_body_state.as_deref().unwrap_or("-")
+                    ));
+                    ui.label(format!(
+                        "Clip: {}",
+                        model.get_animation_name(animation_handler.current_animation).unwrap_or("?")
+                    ));
+
+                    if let Some((anim_player, blender)) = animation_entity_link.main().and_then(|main_player| animation_players.get(main_player).ok()) {
+                        ui.label(format!("Blend duration: {:.2}s", blender.blend_duration()));
+
+                        egui::Grid::new(("animation_nodes", entity)).striped(true).show(ui, |ui| {
+                            ui.label("Clip");
+                            ui.label("Time");
+                            ui.label("Speed");
+                            ui.label("Weight");
+                            ui.label("Target");
+                            ui.end_row();
+
+                            let mut playing: Vec<_> = anim_player.playing_animations().collect();
+                            playing.sort_by_key(|(node, _)| node.index());
+
+                            for (node, active_animation) in playing {
+                                ui.label(node_label(model, *node));
+                                ui.label(format!("{:.2}", active_animation.seek_time()));
+                                ui.label(format!("{:.2}", active_animation.speed()));
+                                ui.label(format!("{:.2}", blender.weights().get(node).copied().unwrap_or(active_animation.weight())));
+                                ui.label(blender.targets().get(node).map_or("fading out".to_string(), |target| format!("{:.2}", target)));
+                                ui.end_row();
+                            }
+                        });
+                    }
+
+                    match forced_animation {
+                        Some(forced_animation) => {
+                            ui.horizontal(|ui| {
+                                ui.label(format!("Forced: {}", forced_animation.0));
+
+                                if ui.button("Release").clicked() {
+                                    actions.push(PanelAction::Release(entity));
+                                }
+                            });
+                        },
+                        None => {
+                            ui.label("Force play:");
+                        }
+                    }
+
+                    let mut clip_names: Vec<&String> = model.animation_name_reference.keys().collect();
+                    clip_names.sort();
+
+                    ui.horizontal_wrapped(|ui| {
+                        for clip_name in clip_names {
+                            if ui.button(clip_name).clicked() {
+                                actions.push(PanelAction::Force(entity, clip_name.clone()));
+                            }
+                        }
+                    });
+                });
+            }
+        });
+    });
+
+    for action in actions {
+        match action {
+            PanelAction::Force(entity, clip_name) => {
+                let Ok((_, _, mut animation_handler, animation_entity_link, _)) = animated_query.get_mut(entity) else {
+                    continue;
+                };
+
+                for linked_player in animation_entity_link.0.iter() {
+                    let Some(model) = animated_models.get(linked_player.model.as_ref().unwrap_or(&animation_handler.model)) else {
+                        continue;
+                    };
+
+                    let Some((animation_index, animation_node)) = model
+                        .get_animation_name_reference(&clip_name)
+                        .and_then(|animation_index| Some((*animation_index, *model.get_animations(*animation_index)?)))
+                    else {
+                        continue;
+                    };
+
+                    let Ok((mut anim_player, mut blender)) = animation_players.get_mut(linked_player.entity) else {
+                        continue;
+                    };
+
+                    anim_player.start(animation_node).repeat();
+                    blender.blend_to([(animation_node, 1.)], 0.2);
+
+                    if linked_player.model.is_none() {
+                        animation_handler.current_animation = animation_index;
+                    }
+                }
+
+                // The state machine starts over once the clip is released.
+                animation_handler.current_state = None;
+                animation_handler.upper_body_state = None;
+
+                commands.entity(entity).insert(ForcedAnimation(clip_name));
+            },
+            PanelAction::Release(entity) => {
+                commands.entity(entity).remove::<ForcedAnimation>();
+            }
+        }
+    }
+}
+
+// Clip name of a node in the model's graph, with the body layer it plays on.
+fn node_label(model: &AnimatedModel, node: AnimationNodeIndex) -> String {
+    if let Some(animation_index) = model.animations.iter().position(|animation_node| *animation_node == node) {
+        return model.get_animation_name(animation_index).unwrap_or("?").to_string();
+    }
+
+    model.body_layer_animations
+        .iter()
+        .enumerate()
+        .find_map(|(animation_index, (upper_body, lower_body))| {
+            let layer = if *upper_body == node {
+                "upper body"
+            } else if *lower_body == node {
+                "lower body"
+            } else {
+                return None;
+            };
+
+            Some(format!("{} ({})", model.get_animation_name(animation_index).unwrap_or("?"), layer))
+        })
+        .unwrap_or_else(|| format!("{:?}", node))
+}
@@ -0,0 +1,324 @@
+use avian3d::prelude::{SpatialQuery, SpatialQueryFilter};
+use bevy::{ecs::system::SystemParam, prelude::*, transform::helper::TransformHelper};
+use bevy_tnua::prelude::TnuaController;
+use serde::Deserialize;
+
+use crate::{
+    asset_loader::{AnimatedModels, AssetLoadingState},
+    AnimationEntityLink
+};
+
+use super::{root_motion::extract_root_motion, state_machine::AnimationStateMachine, AnimationHandler};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(PostUpdate, (
+            apply_foot_ik
+        )
+            .after(bevy::app::Animation)
+            .after(extract_root_motion)
+            .before(TransformSystem::TransformPropagate)
+            .run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Bone names of one leg, from the hip joint down. `lower` has to be a child of `upper` and
+// `foot` a child of `lower`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LegBones {
+    pub upper: String,
+    pub lower: String,
+    pub foot: String,
+}
+
+// Keeps the feet of a character on uneven ground: each foot moves up or down to the ground
+// below it, the hips drop so the lower foot can reach, and the feet tilt with the slope.
+#[derive(Component, Debug)]
+pub struct FootIk {
+    pub enabled: bool,
+    // How far the ground under a foot may be above or below the ground under the character.
+    pub max_step: f32,
+    pub max_hip_drop: f32,
+    // Radians a foot may tilt to follow the ground.
+    pub max_foot_angle: f32,
+    // How quickly the hips follow, per second.
+    pub hip_speed: f32,
+    hip_offset: f32,
+    bones: Option<FootIkBones>,
+}
+
+impl Default for FootIk {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_step: 2.,
+            max_hip_drop: 1.5,
+            max_foot_angle: 0.6,
+            hip_speed: 8.,
+            hip_offset: 0.,
+            bones: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FootIkBones {
+    hips: Option<Entity>,
+    // Upper, lower and foot bone of each leg.
+    legs: Vec<[Entity; 3]>,
+}
+
+struct LegPose {
+    bones: [Entity; 3],
+    transforms: [GlobalTransform; 3],
+    ground_offset: f32,
+    ground_normal: Vec3,
+}
+
+// The model's bone hierarchy, to find the leg bones and the hips' parent.
+#[derive(SystemParam)]
+pub(super) struct BoneHierarchy<'w, 's> {
+    children: Query<'w, 's, &'static Children>,
+    names: Query<'w, 's, &'static Name>,
+    parents: Query<'w, 's, &'static Parent>,
+}
+
+type FootIkQueryData<'a> = (
+    Entity,
+    &'a AnimationHandler,
+    &'a AnimationEntityLink,
+    &'a GlobalTransform,
+    Option<&'a TnuaController>,
+    &'a mut FootIk,
+);
+
+pub(super) fn apply_foot_ik(
+    animated_models: Res<AnimatedModels>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    time: Res<Time>,
+    spatial_query: SpatialQuery,
+    mut character_query: Query<FootIkQueryData>,
+    bone_hierarchy: BoneHierarchy,
+    mut transforms: ParamSet<(TransformHelper, Query<&mut Transform>)>,
+) {
+    for (entity, animation_handler, animation_entity_link, character_transform, tnua_controller, mut foot_ik) in character_query.iter_mut() {
+        let Some(state_machine) = animated_models
+            .get(&animation_handler.model)
+            .and_then(|model| model.get_state_machine(&state_machines))
+            .filter(|state_machine| !state_machine.legs.is_empty())
+        else {
+            continue;
+        };
+
+        let airborne = tnua_controller.is_some_and(|controller| controller.is_airborne().unwrap_or(false));
+
+        if !foot_ik.enabled || airborne {
+            foot_ik.hip_offset = 0.;
+            continue;
+        }
+
+        let bones = foot_ik.bones.get_or_insert_with(|| {
+            let find_bone = |name: &str| {
+                let bone = animation_entity_link.main().and_then(|main_player| {
+                    bone_hierarchy
+                        .children
+                        .iter_descendants(main_player)
+                        .find(|descendant| bone_hierarchy.names.get(*descendant).is_ok_and(|bone_name| bone_name.as_str() == name))
+                });
+
+                if bone.is_none() {
+                    warn!("{} model: foot IK bone {} not found", animation_handler.model, name);
+                }
+
+                bone
+            };
+
+            FootIkBones {
+                hips: state_machine.root_bone.as_deref().and_then(find_bone),
+                legs: state_machine
+                    .legs
+                    .iter()
+                    .filter_map(|leg| Some([find_bone(&leg.upper)?, find_bone(&leg.lower)?, find_bone(&leg.foot)?]))
+                    .collect(),
+            }
+        }).clone();
+
+        let filter = SpatialQueryFilter::from_excluded_entities([entity]);
+        let max_step = foot_ik.max_step;
+
+        let Some(ground) = spatial_query.cast_ray(character_transform.translation(), Dir3::NEG_Y, 20., true, &filter) else {
+            continue;
+        };
+        let ground_height = character_transform.translation().y - ground.distance;
+
+        // The animated pose, before any IK.
+        let transform_helper = transforms.p0();
+
+        let legs: Vec<LegPose> = bones.legs.iter().filter_map(|leg| {
+            let leg_transforms = [
+                transform_helper.compute_global_transform(leg[0]).ok()?,
+                transform_helper.compute_global_transform(leg[1]).ok()?,
+                transform_helper.compute_global_transform(leg[2]).ok()?,
+            ];
+
+            let ray_origin = leg_transforms[2].translation() + Vec3::Y * max_step;
+            let (ground_offset, ground_normal) = spatial_query
+                .cast_ray(ray_origin, Dir3::NEG_Y, max_step * 2., true, &filter)
+                .map_or((0., Vec3::Y), |hit| (ray_origin.y - hit.distance - ground_height, hit.normal));
+
+            Some(LegPose {
+                bones: *leg,
+                transforms: leg_transforms,
+                ground_offset: ground_offset.clamp(-max_step, max_step),
+                ground_normal,
+            })
+        }).collect();
+
+        let hips_parent_transform = bones.hips
+            .and_then(|hips| bone_hierarchy.parents.get(hips).ok())
+            .and_then(|parent| transform_helper.compute_global_transform(parent.get()).ok());
+
+        // Drop the hips for the lowest foot, raising them would leave the other leg dangling.
+        let hip_target = legs
+            .iter()
+            .map(|leg| leg.ground_offset)
+            .fold(0., f32::min)
+            .max(-foot_ik.max_hip_drop);
+        let hip_step = (foot_ik.hip_speed * time.delta_secs()).min(1.);
+        foot_ik.hip_offset += (hip_target - foot_ik.hip_offset) * hip_step;
+
+        let hip_offset = foot_ik.hip_offset;
+        let max_foot_angle = foot_ik.max_foot_angle;
+        let mut local_transforms = transforms.p1();
+
+        if let (Some(hips), Some(hips_parent_transform)) = (bones.hips, hips_parent_transform) {
+            if let Ok(mut hips_transform) = local_transforms.get_mut(hips) {
+                hips_transform.translation += hips_parent_transform.affine().inverse().transform_vector3(Vec3::Y * hip_offset);
+            }
+        }
+
+        for leg in legs {
+            let [upper, lower, foot] = leg.transforms.map(|transform| transform.to_scale_rotation_translation());
+            let hip_shift = Vec3::Y * hip_offset;
+            let target = foot.2 + Vec3::Y * leg.ground_offset;
+
+            let Some((upper_rotation, lower_rotation)) = solve_two_bone_ik(
+                upper.2 + hip_shift,
+                lower.2 + hip_shift,
+                foot.2 + hip_shift,
+                target,
+                upper.1,
+                lower.1,
+                character_transform.right().as_vec3(),
+            ) else {
+                continue;
+            };
+
+            // Global rotations after the IK, to keep the tilted foot's world rotation.
+            let upper_global = upper.1 * upper_rotation;
+            let lower_global = upper_global * upper.1.inverse() * lower.1 * lower_rotation;
+            let foot_global = limited_tilt(leg.ground_normal, max_foot_angle) * foot.1;
+
+            if let Ok(mut transform) = local_transforms.get_mut(leg.bones[0]) {
+                transform.rotation *= upper_rotation;
+            }
+
+            if let Ok(mut transform) = local_transforms.get_mut(leg.bones[1]) {
+                transform.rotation *= lower_rotation;
+            }
+
+            if let Ok(mut transform) = local_transforms.get_mut(leg.bones[2]) {
+                transform.rotation = lower_global.inverse() * foot_global;
+            }
+        }
+    }
+}
+
+// Rotation from up to the ground normal, at most `max_angle` radians.
+fn limited_tilt(normal: Vec3, max_angle: f32) -> Quat {
+    let (axis, angle) = Quat::from_rotation_arc(Vec3::Y, normal.normalize_or(Vec3::Y)).to_axis_angle();
+    Quat::from_axis_angle(axis, angle.min(max_angle))
+}
+
+// Analytic two-bone IK (https://theorangeduck.com/page/simple-two-joint) for the joints `a`, `b`
+// and end `c` in world space. Returns the extra local rotations of the upper and lower bone that
+// put `c` on `target`, bending the knee the way it is already bent.
+fn solve_two_bone_ik(a: Vec3, b: Vec3, c: Vec3, target: Vec3, a_rotation: Quat, b_rotation: Quat, fallback_axis: Vec3) -> Option<(Quat, Quat)> {
+    const EPSILON: f32 = 0.01;
+
+    let upper_length = a.distance(b);
+    let lower_length = b.distance(c);
+
+    if upper_length < EPSILON || lower_length < EPSILON {
+        return None;
+    }
+
+    let target_length = a.distance(target).clamp(EPSILON, upper_length + lower_length - EPSILON);
+    let angle = |x: Vec3, y: Vec3| x.normalize_or_zero().dot(y.normalize_or_zero()).clamp(-1., 1.).acos();
+    let law_of_cosines = |opposite: f32, side_a: f32, side_b: f32| {
+        ((opposite * opposite - side_a * side_a - side_b * side_b) / (-2. * side_a * side_b)).clamp(-1., 1.).acos()
+    };
+
+    let hip_angle = angle(c - a, b - a);
+    let knee_angle = angle(a - b, c - b);
+    let target_angle = angle(c - a, target - a);
+
+    let hip_angle_wanted = law_of_cosines(lower_length, upper_length, target_length);
+    let knee_angle_wanted = law_of_cosines(target_length, upper_length, lower_length);
+
+    let bend_axis = (c - a).cross(b - a).try_normalize().unwrap_or(fallback_axis);
+    let swing_axis = (c - a).cross(target - a).try_normalize().unwrap_or(bend_axis);
+
+    let bend = Quat::from_axis_angle(a_rotation.inverse() * bend_axis, hip_angle_wanted - hip_angle);
+    let knee = Quat::from_axis_angle(b_rotation.inverse() * bend_axis, knee_angle_wanted - knee_angle);
+    let swing = Quat::from_axis_angle(a_rotation.inverse() * swing_axis, target_angle);
+
+    // Bending keeps the hip to foot direction, so the swing can turn the bent leg afterwards.
+    Some((swing * bend, knee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where the end of the chain ends up after applying the solved rotations, for bones that
+    // start out unrotated.
+    fn solved_end(a: Vec3, b: Vec3, c: Vec3, (upper_rotation, lower_rotation): (Quat, Quat)) -> (Vec3, Vec3) {
+        let b_solved = a + upper_rotation * (b - a);
+        let c_solved = b_solved + upper_rotation * lower_rotation * (c - b);
+
+        (b_solved, c_solved)
+    }
+
+    #[test]
+    fn reachable_targets_are_reached_with_the_knee_bent_the_same_way() {
+        let (a, b, c) = (Vec3::new(0., 2., 0.), Vec3::new(0., 1., 0.2), Vec3::ZERO);
+
+        for target in [Vec3::new(0., 0.5, 0.1), Vec3::new(0., 0.3, 0.6), Vec3::new(0.2, 0.4, -0.3)] {
+            let rotations = solve_two_bone_ik(a, b, c, target, Quat::IDENTITY, Quat::IDENTITY, Vec3::X).unwrap();
+            let (b_solved, c_solved) = solved_end(a, b, c, rotations);
+
+            assert!(c_solved.distance(target) < 1e-3, "{} instead of {}", c_solved, target);
+            assert!((b_solved.distance(a) - b.distance(a)).abs() < 1e-4);
+            assert!((c_solved.distance(b_solved) - c.distance(b)).abs() < 1e-4);
+            assert!(b_solved.z > target.z.min(0.), "knee flipped to {}", b_solved);
+        }
+    }
+
+    #[test]
+    fn unreachable_targets_stretch_the_leg_towards_them() {
+        let (a, b, c) = (Vec3::new(0., 2., 0.), Vec3::new(0., 1., 0.2), Vec3::ZERO);
+        let target = Vec3::new(0., -5., 1.);
+
+        let rotations = solve_two_bone_ik(a, b, c, target, Quat::IDENTITY, Quat::IDENTITY, Vec3::X).unwrap();
+        let (_, c_solved) = solved_end(a, b, c, rotations);
+        let leg_length = a.distance(b) + b.distance(c);
+
+        assert!((c_solved - a).normalize().dot((target - a).normalize()) > 0.999);
+        assert!(c_solved.distance(a) > leg_length - 0.05 && c_solved.distance(a) <= leg_length);
+    }
+
+    #[test]
+    fn zero_length_bones_are_not_solved() {
+        assert!(solve_two_bone_ik(Vec3::ZERO, Vec3::ZERO, Vec3::NEG_Y, Vec3::NEG_Y, Quat::IDENTITY, Quat::IDENTITY, Vec3::X).is_none());
+    }
+}
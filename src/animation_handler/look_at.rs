@@ -0,0 +1,163 @@
+use bevy::{prelude::*, transform::helper::TransformHelper};
+use serde::Deserialize;
+
+use crate::{
+    asset_loader::{AnimatedModels, AssetLoadingState},
+    AnimationEntityLink
+};
+
+use super::{foot_ik::apply_foot_ik, state_machine::AnimationStateMachine, AnimationHandler};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(PostUpdate, (
+            apply_look_at
+        )
+            .after(bevy::app::Animation)
+            .after(apply_foot_ik)
+            .before(TransformSystem::TransformPropagate)
+            .run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// A bone turning towards the look target, with its share of the turn. Listed from the spine up
+// to the head, the shares should add up to 1.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LookAtBone {
+    pub bone: String,
+    pub weight: f32,
+}
+
+// Turns the torso and head towards `target` on top of the current animation, within limits.
+#[derive(Component, Debug)]
+pub struct LookAt {
+    // World position to look at, the head turns back to the animation while `None`.
+    pub target: Option<Vec3>,
+    // Direction the model faces in the entity's local space.
+    pub forward: Vec3,
+    pub max_yaw: f32,
+    pub max_pitch: f32,
+    // Radians per second.
+    pub turn_speed: f32,
+    yaw: f32,
+    pitch: f32,
+    bones: Option<Vec<(Entity, f32)>>,
+}
+
+impl Default for LookAt {
+    fn default() -> Self {
+        Self {
+            target: None,
+            forward: Vec3::NEG_Z,
+            max_yaw: 70_f32.to_radians(),
+            max_pitch: 40_f32.to_radians(),
+            turn_speed: 4.,
+            yaw: 0.,
+            pitch: 0.,
+            bones: None,
+        }
+    }
+}
+
+impl LookAt {
+    pub fn with_forward(mut self, forward: Vec3) -> Self {
+        self.forward = forward;
+        self
+    }
+}
+
+pub(super) fn apply_look_at(
+    animated_models: Res<AnimatedModels>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    time: Res<Time>,
+    mut character_query: Query<(&AnimationHandler, &AnimationEntityLink, &GlobalTransform, &mut LookAt)>,
+    children_query: Query<&Children>,
+    name_query: Query<&Name>,
+    mut transforms: ParamSet<(TransformHelper, Query<&mut Transform>)>,
+) {
+    for (animation_handler, animation_entity_link, character_transform, mut look_at) in character_query.iter_mut() {
+        let Some(state_machine) = animated_models
+            .get(&animation_handler.model)
+            .and_then(|model| model.get_state_machine(&state_machines))
+            .filter(|state_machine| !state_machine.look_at_bones.is_empty())
+        else {
+            continue;
+        };
+
+        let bones = look_at.bones.get_or_insert_with(|| {
+            state_machine.look_at_bones.iter().filter_map(|look_at_bone| {
+                let bone = animation_entity_link.main().and_then(|main_player| {
+                    children_query
+                        .iter_descendants(main_player)
+                        .find(|descendant| name_query.get(*descendant).is_ok_and(|name| name.as_str() == look_at_bone.bone))
+                });
+
+                if bone.is_none() {
+                    warn!("{} model: look at bone {} not found", animation_handler.model, look_at_bone.bone);
+                }
+
+                bone.map(|bone| (bone, look_at_bone.weight))
+            }).collect()
+        }).clone();
+
+        // The animated pose, before turning anything.
+        let transform_helper = transforms.p0();
+
+        let Some(bone_rotations) = bones
+            .iter()
+            .map(|(bone, _)| transform_helper.compute_global_transform(*bone).ok().map(|transform| transform.to_scale_rotation_translation().1))
+            .collect::<Option<Vec<Quat>>>()
+        else {
+            continue;
+        };
+
+        let Some(head_position) = bones
+            .last()
+            .and_then(|(head, _)| transform_helper.compute_global_transform(*head).ok())
+            .map(|transform| transform.translation())
+        else {
+            continue;
+        };
+
+        let Some(facing) = (character_transform.rotation() * look_at.forward).with_y(0.).try_normalize() else {
+            continue;
+        };
+
+        let (target_yaw, target_pitch) = look_at.target
+            .and_then(|target| (target - head_position).try_normalize())
+            .map_or((0., 0.), |direction| {
+                let yaw = facing.xz().angle_to(direction.xz());
+                let pitch = direction.y.clamp(-1., 1.).asin();
+                // `angle_to` is counter-clockwise seen from above, turning around Y is the other way.
+                (-yaw, pitch)
+            });
+
+        let max_turn = look_at.turn_speed * time.delta_secs();
+        let target_yaw = target_yaw.clamp(-look_at.max_yaw, look_at.max_yaw);
+        let target_pitch = target_pitch.clamp(-look_at.max_pitch, look_at.max_pitch);
+        look_at.yaw += (target_yaw - look_at.yaw).clamp(-max_turn, max_turn);
+        look_at.pitch += (target_pitch - look_at.pitch).clamp(-max_turn, max_turn);
+
+        if look_at.yaw.abs() < 1e-4 && look_at.pitch.abs() < 1e-4 {
+            continue;
+        }
+
+        let (yaw, pitch) = (look_at.yaw, look_at.pitch);
+        let pitch_axis = facing.cross(Vec3::Y);
+        let mut local_transforms = transforms.p1();
+
+        // Each bone inherits the turn of the bones before it in the list, its parents from the
+        // spine up, so only its own share is added on top.
+        let mut applied = Quat::IDENTITY;
+
+        for ((bone, weight), rotation) in bones.iter().zip(bone_rotations) {
+            let turn = Quat::from_rotation_y(yaw * weight) * Quat::from_axis_angle(pitch_axis, pitch * weight);
+            let global_rotation = applied * rotation;
+
+            if let Ok(mut transform) = local_transforms.get_mut(*bone) {
+                transform.rotation *= global_rotation.inverse() * turn * global_rotation;
+            }
+
+            applied = turn * applied;
+        }
+    }
+}
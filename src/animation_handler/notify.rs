@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    asset_loader::{AnimatedModels, AssetLoadingState},
+    AnimationEntityLink
+};
+
+use super::{state_machine::AnimationStateMachine, AnimationHandler};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_event::<AnimationNotifyEvent>()
+        .add_systems(PostUpdate, (
+            emit_animation_notifies
+        ).after(bevy::app::Animation).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationNotify {
+    // The weapon starts and stops dealing damage.
+    HitStart,
+    HitEnd,
+    Footstep,
+    // The rest of the attack animation is just recovery, the next action may start.
+    CanCancel
+}
+
+// A marker on a clip, `time` in seconds into the clip (before any playback speed).
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnimationNotifyMarker {
+    pub time: f32,
+    pub notify: AnimationNotify,
+}
+
+// Sent once playback of a clip crosses one of its markers. `entity` is the animated entity
+// (the one with the `AnimationHandler`), not the scene's `AnimationPlayer`.
+#[derive(Event, Debug, Clone)]
+pub struct AnimationNotifyEvent {
+    pub entity: Entity,
+    pub notify: AnimationNotify,
+}
+
+// Where every playing clip node was at the end of the last frame, as (seek time, completions).
+#[derive(Component, Debug, Default)]
+pub struct AnimationNotifyTracker {
+    playback: HashMap<AnimationNodeIndex, (f32, u32)>,
+}
+
+pub fn emit_animation_notifies(
+    animated_models: Res<AnimatedModels>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    animation_players: Query<&AnimationPlayer>,
+    mut animated_query: Query<(Entity, &AnimationHandler, &AnimationEntityLink, &mut AnimationNotifyTracker)>,
+    mut notify_events: EventWriter<AnimationNotifyEvent>,
+) {
+    for (entity, animation_handler, animation_entity_link, mut tracker) in animated_query.iter_mut() {
+        let Some(model) = animated_models.get(&animation_handler.model) else {
+            continue;
+        };
+
+        let Some(state_machine) = model.get_state_machine(&state_machines).filter(|state_machine| !state_machine.notifies.is_empty()) else {
+            continue;
+        };
+
+        let Some(anim_player) = animation_entity_link.main().and_then(|main_player| animation_players.get(main_player).ok()) else {
+            continue;
+        };
+
+        let mut playback = HashMap::new();
+
+        for (clip, markers) in state_machine.notifies.iter() {
+            let Some(animation_index) = model.get_animation_name_reference(clip).copied() else {
+                continue;
+            };
+
+            // The same clip may play on the full body and a body layer at once while they
+            // cross-fade, it still only notifies once.
+            let nodes = model
+                .get_animations(animation_index)
+                .copied()
+                .into_iter()
+                .chain(model.get_body_layer_animations(animation_index).into_iter().flat_map(|(upper_body, lower_body)| [*upper_body, *lower_body]));
+
+            let mut crossed_notifies: Vec<AnimationNotify> = Vec::new();
+
+            for node in nodes {
+                let Some(active_animation) = anim_player.animation(node) else {
+                    continue;
+                };
+
+                if active_animation.is_paused() || active_animation.weight() <= 0. {
+                    continue;
+                }
+
+                let current = (active_animation.seek_time(), active_animation.completions());
+                let previous = tracker.playback.get(&node).copied();
+                playback.insert(node, current);
+
+                for marker in markers {
+                    if crossed(previous, current, marker.time) && !crossed_notifies.contains(&marker.notify) {
+                        crossed_notifies.push(marker.notify);
+                    }
+                }
+            }
+
+            for notify in crossed_notifies {
+                notify_events.send(AnimationNotifyEvent { entity, notify });
+            }
+        }
+
+        tracker.playback = playback;
+    }
+}
+
+// Whether playback went past `time` since last frame, counting clips that looped or restarted.
+fn crossed(previous: Option<(f32, u32)>, (seek_time, completions): (f32, u32), time: f32) -> bool {
+    match previous {
+        Some((previous_time, _)) if seek_time >= previous_time => previous_time < time && time <= seek_time,
+        // Looped: the end of the last run and the start of this one.
+        Some((previous_time, previous_completions)) if completions > previous_completions => time > previous_time || time <= seek_time,
+        // Just started, or started over.
+        _ => time <= seek_time
+    }
+}
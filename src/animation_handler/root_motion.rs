@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{
+    asset_loader::{AnimatedModels, AssetLoadingState},
+    AnimationEntityLink
+};
+
+use super::{state_machine::AnimationStateMachine, AnimationHandler};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(PostUpdate, (
+            extract_root_motion
+        ).after(bevy::app::Animation).before(TransformSystem::TransformPropagate).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Movement the current animation state authored on the model's root bone. While `active` the
+// movement systems move the entity with `velocity` instead of their own, and the bone is kept
+// in place horizontally so the model doesn't run off its collider.
+#[derive(Component, Debug, Default)]
+pub struct RootMotion {
+    pub active: bool,
+    // Horizontal world space velocity, one frame behind the animation.
+    pub velocity: Vec3,
+    // Looked up the first time it's needed, `Some(None)` when the model doesn't have it.
+    bone: Option<Option<Entity>>,
+    state: Option<String>,
+    // Bone translation (in its parent's space) when the state started and at the end of last frame.
+    origin: Vec3,
+    previous: Option<Vec3>,
+}
+
+impl RootMotion {
+    fn stop(&mut self) {
+        self.active = false;
+        self.velocity = Vec3::ZERO;
+        self.state = None;
+        self.previous = None;
+    }
+}
+
+// The model's bone hierarchy, to find the root bone and keep it in place.
+#[derive(SystemParam)]
+pub(super) struct BoneQueries<'w, 's> {
+    children: Query<'w, 's, &'static Children>,
+    names: Query<'w, 's, &'static Name>,
+    parents: Query<'w, 's, &'static Parent>,
+    global_transforms: Query<'w, 's, &'static GlobalTransform>,
+    transforms: Query<'w, 's, &'static mut Transform>,
+}
+
+pub(super) fn extract_root_motion(
+    animated_models: Res<AnimatedModels>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    time: Res<Time>,
+    mut animated_query: Query<(&AnimationHandler, &AnimationEntityLink, &mut RootMotion)>,
+    mut bone_queries: BoneQueries,
+    mut models_missing_root_bone: Local<HashSet<String>>,
+) {
+    for (animation_handler, animation_entity_link, mut root_motion) in animated_query.iter_mut() {
+        let Some(state_machine) = animated_models
+            .get(&animation_handler.model)
+            .and_then(|model| model.get_state_machine(&state_machines))
+        else {
+            continue;
+        };
+
+        let Some(root_bone) = &state_machine.root_bone else {
+            continue;
+        };
+
+        let root_motion_state = animation_handler
+            .current_state
+            .as_ref()
+            .and_then(|state| state_machine.states.get(state))
+            .is_some_and(|state| state.root_motion);
+
+        if !root_motion_state {
+            root_motion.stop();
+            continue;
+        }
+
+        // The lookup isn't tried again, and a model without the bone is only reported once.
+        let bone = *root_motion.bone.get_or_insert_with(|| {
+            let bone = animation_entity_link.main().and_then(|main_player| {
+                bone_queries
+                    .children
+                    .iter_descendants(main_player)
+                    .find(|descendant| bone_queries.names.get(*descendant).is_ok_and(|name| name.as_str() == root_bone))
+            });
+
+            if bone.is_none() && models_missing_root_bone.insert(animation_handler.model.clone()) {
+                warn!("{} model: root bone {} not found, no root motion", animation_handler.model, root_bone);
+            }
+
+            bone
+        });
+
+        let Some(bone) = bone else {
+            continue;
+        };
+
+        // The bone's parent (the armature) may be scaled and rotated, e.g. Z-up exports.
+        let parent_affine = bone_queries
+            .parents
+            .get(bone)
+            .and_then(|parent| bone_queries.global_transforms.get(parent.get()))
+            .map_or(bevy::math::Affine3A::IDENTITY, |parent_transform| parent_transform.affine());
+        let horizontal = |local: Vec3| parent_affine.transform_vector3(local).with_y(0.);
+
+        let Ok(mut bone_transform) = bone_queries.transforms.get_mut(bone) else {
+            continue;
+        };
+
+        let translation = bone_transform.translation;
+
+        if root_motion.state != animation_handler.current_state {
+            root_motion.state = animation_handler.current_state.clone();
+            root_motion.origin = translation;
+            root_motion.previous = None;
+        }
+
+        let previous = root_motion.previous.unwrap_or(translation);
+        let delta = time.delta_secs();
+
+        root_motion.active = true;
+        root_motion.velocity = if delta > 0. {
+            horizontal(translation - previous) / delta
+        } else {
+            Vec3::ZERO
+        };
+        root_motion.previous = Some(translation);
+
+        let offset = horizontal(translation - root_motion.origin);
+        bone_transform.translation -= parent_affine.inverse().transform_vector3(offset);
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext},
+    prelude::*
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::combat_manager::{AttackTiming, AttackType, CombatAction};
+
+use super::{foot_ik::LegBones, look_at::LookAtBone, notify::AnimationNotifyMarker};
+
+pub fn plugin(app: &mut App) {
+    app
+        .init_asset::<AnimationStateMachine>()
+        .init_asset_loader::<AnimationStateMachineLoader>();
+}
+
+// Which clip plays when, loaded from `assets/animations/*.anim.ron`.
+#[derive(Asset, TypePath, Deserialize, Debug, Clone)]
+pub struct AnimationStateMachine {
+    pub initial_state: String,
+    // Bone the upper body hangs from, e.g. the lowest spine bone. Upper-body attacks play on it
+    // and its children while the locomotion keeps the legs going.
+    #[serde(default)]
+    pub upper_body_bone: Option<String>,
+    // Bone carrying the model's root motion, usually the hips. Its horizontal movement in states
+    // with `root_motion` moves the entity instead.
+    #[serde(default)]
+    pub root_bone: Option<String>,
+    // Legs kept on the ground by `FootIk`, the hips (`root_bone`) drop for the lower foot.
+    #[serde(default)]
+    pub legs: Vec<LegBones>,
+    // Bones turned towards a `LookAt` target.
+    #[serde(default)]
+    pub look_at_bones: Vec<LookAtBone>,
+    pub states: HashMap<String, AnimationStateDefinition>,
+    // Checked in order every frame, the first one that applies decides the next state. Put the
+    // most important ones (attacks) first.
+    pub transitions: Vec<AnimationTransitionDefinition>,
+    // Markers on the model's clips, keyed by clip name, e.g. when an attack starts to hit.
+    #[serde(default)]
+    pub notifies: HashMap<String, Vec<AnimationNotifyMarker>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnimationStateDefinition {
+    // Name of the animation in the glTF.
+    pub clip: String,
+    // Clips tried in order when the model has no `clip`, e.g. HeavyAttack -> LightAttack -> Idle.
+    #[serde(default)]
+    pub fallback_clips: Vec<String>,
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+    #[serde(default)]
+    pub looping: bool,
+    // Plays the clip over the whole attack (windup + attack_time + cooldown) instead of at
+    // `speed`. Only for attacks timed by their timers, animation-timed attacks already follow
+    // the clip's notifies and play it at `speed`.
+    #[serde(default)]
+    pub sync_to_attack: bool,
+    // Moves the entity as the clip moves `root_bone`, e.g. lunging attacks, rolls, hit reactions.
+    #[serde(default)]
+    pub root_motion: bool,
+    // Blends several clips by how the entity moves instead of playing just `clip`, which is
+    // then only used when the model has none of the blend space clips.
+    #[serde(default)]
+    pub blend_space: Option<BlendSpace>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum BlendSpace {
+    // Clips at horizontal speeds, e.g. idle at 0, walk at 4, run at 10.
+    Speed(Vec<(String, f32)>),
+    // Clips at velocities in the entity's local space, sideways (x) and forward (z), so
+    // strafing and backpedalling while facing a target get their own clips.
+    Directional(Vec<(String, f32, f32)>),
+}
+
+impl BlendSpace {
+    pub fn clips(&self) -> Vec<&str> {
+        match self {
+            BlendSpace::Speed(points) => points.iter().map(|(clip, _)| clip.as_str()).collect(),
+            BlendSpace::Directional(points) => points.iter().map(|(clip, _, _)| clip.as_str()).collect(),
+        }
+    }
+
+    // Weight of each clip for the current movement, skipping clips the model doesn't have.
+    // The weights add up to 1, or the result is empty when no clip is available.
+    pub fn weights(&self, context: &AnimationContext, has_clip: impl Fn(&str) -> bool) -> Vec<(&str, f32)> {
+        match self {
+            BlendSpace::Speed(points) => {
+                let mut points: Vec<(&str, f32)> = points
+                    .iter()
+                    .filter(|(clip, _)| has_clip(clip))
+                    .map(|(clip, speed)| (clip.as_str(), *speed))
+                    .collect();
+                points.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+                blend_by_speed(&points, context.speed)
+            }
+            BlendSpace::Directional(points) => {
+                let points: Vec<(&str, Vec2)> = points
+                    .iter()
+                    .filter(|(clip, _, _)| has_clip(clip))
+                    .map(|(clip, x, z)| (clip.as_str(), Vec2::new(*x, *z)))
+                    .collect();
+
+                blend_by_direction(&points, context.local_velocity)
+            }
+        }
+    }
+}
+
+// Linear blend between the two points around `speed`, clamped to the slowest and fastest.
+// Expects the points sorted by speed.
+fn blend_by_speed<'a>(points: &[(&'a str, f32)], speed: f32) -> Vec<(&'a str, f32)> {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return Vec::new();
+    };
+
+    let Some(upper) = points.iter().position(|(_, point_speed)| *point_speed >= speed) else {
+        return vec![(last.0, 1.)];
+    };
+
+    if upper == 0 {
+        return vec![(first.0, 1.)];
+    }
+
+    let (lower_clip, lower_speed) = points[upper - 1];
+    let (upper_clip, upper_speed) = points[upper];
+    let t = (speed - lower_speed) / (upper_speed - lower_speed);
+
+    vec![(lower_clip, 1. - t), (upper_clip, t)]
+}
+
+// Gradient band interpolation: every point only has influence up to its neighbours, so the
+// blend stays between the samples around `velocity` and never leaks into clips on the other side.
+fn blend_by_direction<'a>(points: &[(&'a str, Vec2)], velocity: Vec2) -> Vec<(&'a str, f32)> {
+    let influences: Vec<f32> = points
+        .iter()
+        .enumerate()
+        .map(|(index, (_, point))| {
+            points
+                .iter()
+                .enumerate()
+                .filter(|(other_index, _)| *other_index != index)
+                .map(|(_, (_, other))| {
+                    let edge = *other - *point;
+                    1. - (velocity - *point).dot(edge) / edge.length_squared()
+                })
+                .fold(1_f32, f32::min)
+                .max(0.)
+        })
+        .collect();
+
+    let total: f32 = influences.iter().sum();
+
+    if total <= 0. {
+        // Only possible with overlapping points, fall back to the closest one.
+        return points
+            .iter()
+            .min_by(|(_, a), (_, b)| a.distance_squared(velocity).total_cmp(&b.distance_squared(velocity)))
+            .map(|(clip, _)| vec![(*clip, 1.)])
+            .unwrap_or_default();
+    }
+
+    points
+        .iter()
+        .zip(influences)
+        .filter(|(_, influence)| *influence > 0.)
+        .map(|((clip, _), influence)| (*clip, influence / total))
+        .collect()
+}
+
+fn default_speed() -> f32 {
+    1.
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnimationTransitionDefinition {
+    // Only taken from this state, or from any state when left out.
+    #[serde(default)]
+    pub from: Option<String>,
+    pub to: String,
+    // All of them have to hold.
+    #[serde(default)]
+    pub conditions: Vec<AnimationCondition>,
+    // Cross-fade duration in seconds.
+    #[serde(default)]
+    pub blend: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum AnimationCondition {
+    Airborne,
+    // Horizontal speed, in units per second.
+    SpeedAbove(f32),
+    SpeedBelow(f32),
+    Attacking,
+    Attack(AttackType),
+    Not(Box<AnimationCondition>),
+}
+
+impl AnimationStateDefinition {
+    // The clip and then its fallbacks, in the order they should be tried.
+    pub fn clips(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.clip.as_str()).chain(self.fallback_clips.iter().map(String::as_str))
+    }
+
+    // How fast to play one of the state's clips, `clip_duration` seconds long, while the entity
+    // does `combat_action`. Timer-timed attacks stretch it over the whole attack, so the swing
+    // lines up with the hit window whatever the weapon stats say.
+    pub fn clip_speed(&self, clip_duration: Option<f32>, combat_action: Option<&CombatAction>) -> f32 {
+        let attack_duration = combat_action
+            .filter(|combat_action| self.sync_to_attack && combat_action.timing == AttackTiming::Timers)
+            .map(CombatAction::duration)
+            .filter(|duration| *duration > 0.);
+
+        match (attack_duration, clip_duration) {
+            (Some(attack_duration), Some(clip_duration)) if clip_duration > 0. => clip_duration / attack_duration,
+            _ => self.speed
+        }
+    }
+}
+
+// What an animated entity is doing this frame, checked against the transition conditions.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AnimationContext {
+    pub speed: f32,
+    // Horizontal velocity in the entity's local space, x sideways and y forward (local z).
+    pub local_velocity: Vec2,
+    pub airborne: bool,
+    pub attack: Option<AttackType>,
+}
+
+impl AnimationCondition {
+    fn holds(&self, context: &AnimationContext) -> bool {
+        match self {
+            AnimationCondition::Airborne => context.airborne,
+            AnimationCondition::SpeedAbove(speed) => context.speed > *speed,
+            AnimationCondition::SpeedBelow(speed) => context.speed < *speed,
+            AnimationCondition::Attacking => context.attack.is_some(),
+            AnimationCondition::Attack(attack_type) => context.attack == Some(*attack_type),
+            AnimationCondition::Not(condition) => !condition.holds(context),
+        }
+    }
+}
+
+impl AnimationStateMachine {
+    // The state to be in and the blend to get there, starting from `current` (or the initial
+    // state when nothing plays yet). Stays put when no transition applies.
+    pub fn next_state<'a>(&'a self, current: Option<&'a str>, context: &AnimationContext) -> (&'a str, f32) {
+        let current = current.unwrap_or(&self.initial_state);
+
+        self.transitions
+            .iter()
+            .filter(|transition| transition.from.as_deref().is_none_or(|from| from == current))
+            .find(|transition| transition.conditions.iter().all(|condition| condition.holds(context)))
+            .map_or((current, 0.), |transition| (transition.to.as_str(), transition.blend))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AnimationStateMachineLoaderError> {
+        let state_machine = ron::de::from_bytes::<AnimationStateMachine>(bytes)?;
+
+        // Catch typos in state names at load time rather than silently never transitioning.
+        let referenced_states = std::iter::once(&state_machine.initial_state)
+            .chain(state_machine.transitions.iter().flat_map(|transition| transition.from.iter().chain([&transition.to])));
+
+        for state in referenced_states {
+            if !state_machine.states.contains_key(state) {
+                return Err(AnimationStateMachineLoaderError::UnknownState(state.clone()));
+            }
+        }
+
+        Ok(state_machine)
+    }
+}
+
+#[derive(Default)]
+pub struct AnimationStateMachineLoader;
+
+#[derive(Debug, Error)]
+pub enum AnimationStateMachineLoaderError {
+    #[error("Could not read animation state machine: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse animation state machine: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("Animation state machine refers to unknown state: {0}")]
+    UnknownState(String),
+}
+
+impl AssetLoader for AnimationStateMachineLoader {
+    type Asset = AnimationStateMachine;
+    type Settings = ();
+    type Error = AnimationStateMachineLoaderError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        AnimationStateMachine::from_bytes(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["anim.ron"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCOMOTION: &str = r#"(
+        initial_state: "Idle",
+        states: {
+            "Idle": (clip: "Idle", looping: true),
+            "Run": (clip: "Run", speed: 1.5, looping: true),
+            "Jump": (clip: "Jump"),
+            "Attack": (clip: "Attack"),
+        },
+        transitions: [
+            (to: "Attack", conditions: [Attacking], blend: 0.05),
+            (to: "Jump", conditions: [Airborne], blend: 0.1),
+            (from: Some("Idle"), to: "Run", conditions: [SpeedAbove(1.0), Not(Airborne)], blend: 0.2),
+            (from: Some("Run"), to: "Idle", conditions: [SpeedBelow(1.0)], blend: 0.3),
+        ],
+    )"#;
+
+    fn state_machine() -> AnimationStateMachine {
+        AnimationStateMachine::from_bytes(LOCOMOTION.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn starts_from_the_initial_state() {
+        assert_eq!(state_machine().next_state(None, &AnimationContext::default()), ("Idle", 0.));
+    }
+
+    #[test]
+    fn takes_the_first_transition_that_applies() {
+        let state_machine = state_machine();
+        let context = AnimationContext {
+            speed: 5.,
+            airborne: true,
+            attack: Some(AttackType::Light),
+            ..default()
+        };
+
+        assert_eq!(state_machine.next_state(Some("Run"), &context), ("Attack", 0.05));
+        assert_eq!(state_machine.next_state(Some("Run"), &AnimationContext { attack: None, ..context }), ("Jump", 0.1));
+    }
+
+    #[test]
+    fn transitions_only_leave_their_from_state() {
+        let state_machine = state_machine();
+        let running = AnimationContext { speed: 5., ..default() };
+        let standing = AnimationContext::default();
+
+        assert_eq!(state_machine.next_state(Some("Idle"), &running), ("Run", 0.2));
+        assert_eq!(state_machine.next_state(Some("Run"), &standing), ("Idle", 0.3));
+        // Nothing leads out of a finished jump while grounded and still, so it stays put.
+        assert_eq!(state_machine.next_state(Some("Jump"), &standing), ("Jump", 0.));
+    }
+
+    #[test]
+    fn loader_fills_in_defaults() {
+        let state_machine = state_machine();
+
+        assert_eq!(state_machine.states["Idle"].speed, 1.);
+        assert!(state_machine.states["Idle"].looping);
+        assert_eq!(state_machine.states["Run"].speed, 1.5);
+        assert!(!state_machine.states["Jump"].looping);
+    }
+
+    #[test]
+    fn loader_rejects_unknown_states() {
+        let typo = LOCOMOTION.replace(r#"to: "Jump""#, r#"to: "Jmup""#);
+
+        assert!(matches!(
+            AnimationStateMachine::from_bytes(typo.as_bytes()),
+            Err(AnimationStateMachineLoaderError::UnknownState(state)) if state == "Jmup"
+        ));
+        assert!(matches!(
+            AnimationStateMachine::from_bytes(b"(initial_state: \"Idle\")"),
+            Err(AnimationStateMachineLoaderError::Ron(_))
+        ));
+    }
+
+    fn attack_state(sync_to_attack: bool) -> AnimationStateDefinition {
+        ron::de::from_str(&format!("(clip: \"Attack\", speed: 2.0, sync_to_attack: {})", sync_to_attack)).unwrap()
+    }
+
+    #[test]
+    fn sync_to_attack_stretches_the_clip_over_timer_timed_attacks() {
+        let state = attack_state(true);
+        // 0.25 + 0.5 + 0.25 seconds in all.
+        let attack = CombatAction::new(AttackType::Light, 0.25, 0.5, 0.25, 1.);
+
+        assert_eq!(state.clip_speed(Some(2.), Some(&attack)), 2.);
+        assert_eq!(state.clip_speed(Some(0.5), Some(&attack)), 0.5);
+        assert_eq!(state.clip_speed(None, Some(&attack)), 2.);
+        assert_eq!(state.clip_speed(Some(0.5), None), 2.);
+        assert_eq!(attack_state(false).clip_speed(Some(0.5), Some(&attack)), 2.);
+    }
+
+    #[test]
+    fn sync_to_attack_leaves_animation_timed_attacks_at_their_speed() {
+        let state = attack_state(true);
+        let attack = CombatAction::new(AttackType::Light, 0.25, 0.5, 0.25, 1.).with_timing(AttackTiming::Animation);
+
+        assert_eq!(state.clip_speed(Some(0.5), Some(&attack)), 2.);
+    }
+
+    #[test]
+    fn shipped_state_machines_load() {
+        for bytes in [
+            include_bytes!("../../assets/animations/alien.anim.ron").as_slice(),
+            include_bytes!("../../assets/animations/dogman.anim.ron").as_slice(),
+        ] {
+            AnimationStateMachine::from_bytes(bytes).unwrap();
+        }
+    }
+
+    fn directional() -> Vec<(&'static str, Vec2)> {
+        vec![
+            ("Idle", Vec2::new(0., 0.)),
+            ("Walking", Vec2::new(0., 6.)),
+            ("Running", Vec2::new(0., 20.)),
+            ("WalkingBackwards", Vec2::new(0., -6.)),
+            ("StrafeLeft", Vec2::new(6., 0.)),
+            ("StrafeRight", Vec2::new(-6., 0.)),
+        ]
+    }
+
+    fn weight(weights: &[(&str, f32)], clip: &str) -> f32 {
+        weights.iter().find(|(weight_clip, _)| *weight_clip == clip).map_or(0., |(_, weight)| *weight)
+    }
+
+    fn assert_normalized(weights: &[(&str, f32)]) {
+        let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+        assert!((total - 1.).abs() < 1e-5, "weights add up to {}", total);
+    }
+
+    #[test]
+    fn speed_blend_interpolates_between_neighbours() {
+        let points = [("Idle", 0.), ("Walking", 6.), ("Running", 20.)];
+
+        assert_eq!(blend_by_speed(&points, 13.), vec![("Walking", 0.5), ("Running", 0.5)]);
+        assert_eq!(blend_by_speed(&points, 30.), vec![("Running", 1.)]);
+        assert_eq!(blend_by_speed(&points, -1.), vec![("Idle", 1.)]);
+    }
+
+    #[test]
+    fn direction_blend_plays_a_sample_on_its_own() {
+        let weights = blend_by_direction(&directional(), Vec2::new(0., 6.));
+
+        assert_eq!(weights, vec![("Walking", 1.)]);
+    }
+
+    #[test]
+    fn direction_blend_stays_between_the_neighbouring_samples() {
+        let weights = blend_by_direction(&directional(), Vec2::new(0., 10.));
+
+        assert_normalized(&weights);
+        assert_eq!(weights.len(), 2);
+        assert!((weight(&weights, "Walking") - 5. / 7.).abs() < 1e-5);
+        assert!((weight(&weights, "Running") - 2. / 7.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn direction_blend_does_not_leak_into_opposite_clips() {
+        for velocity in [Vec2::new(0., 3.), Vec2::new(2., 5.), Vec2::new(5., 1.), Vec2::new(0., 40.)] {
+            let weights = blend_by_direction(&directional(), velocity);
+
+            assert_normalized(&weights);
+            assert_eq!(weight(&weights, "WalkingBackwards"), 0., "{:?}: {:?}", velocity, weights);
+            assert_eq!(weight(&weights, "StrafeRight"), 0., "{:?}: {:?}", velocity, weights);
+        }
+    }
+
+    #[test]
+    fn direction_blend_mixes_forward_and_strafe_on_diagonals() {
+        let weights = blend_by_direction(&directional(), Vec2::new(3., 3.));
+
+        assert_normalized(&weights);
+        assert!(weight(&weights, "Walking") > 0.);
+        assert!((weight(&weights, "Walking") - weight(&weights, "StrafeLeft")).abs() < 1e-5);
+        assert_eq!(weight(&weights, "Running"), 0.);
+    }
+}
@@ -1,7 +1,7 @@
-use bevy::{asset::AssetIndex, gltf::GltfNode, prelude::*, reflect::Map};
-use std::collections::HashMap;
+use bevy::{animation::AnimationTargetId, asset::{AssetIndex, LoadState, LoadedFolder, RecursiveDependencyLoadState}, ecs::system::SystemParam, gltf::GltfNode, prelude::*, reflect::Map};
+use std::collections::{HashMap, HashSet};
 
-use crate::animation_handler::ResourceHandle;
+use crate::{animation_handler::state_machine::AnimationStateMachine, enemy::archetype::EnemyArchetype, AnimatedAttachment};
 
 #[derive(Resource)]
 pub struct MyAssets {
@@ -23,7 +23,11 @@ pub fn plugin(app: &mut App) {
         .init_state::<AssetLoadingState>()
         .add_systems(Startup, setup)
         .add_systems(Update, (wait_for_gltf_to_load).run_if(in_state(AssetLoadingState::Init2)))
-        .add_systems(OnEnter(AssetLoadingState::Loading), parse_gltf);
+        .add_systems(OnEnter(AssetLoadingState::Loading), parse_gltf)
+        .add_systems(Update, (
+            register_attachment_models,
+            build_attachment_models
+        ).chain().run_if(in_state(AssetLoadingState::Loaded)));
 }
 
 #[derive(States, Default, Debug, Clone, Eq, PartialEq, Hash)]
@@ -35,99 +39,144 @@ pub enum AssetLoadingState {
     Loaded
 }
 
-#[derive(Resource)]
-pub struct CharacterHandle {
-    pub scene: Handle<Scene>,
-    pub animations: Vec<AnimationNodeIndex>,
-    pub animation_graph: Handle<AnimationGraph>,
-    pub animation_name_reference: HashMap<String, usize>,
-}
-
-#[derive(Resource)]
-pub struct DogmanGltf {
-    pub gltf: Handle<Gltf>,
-}
+// Models animated through an `AnimationStateMachine` are keyed by their glTF path, the id
+// entities refer to in `AnimationHandler::model`. Enemy models come from the loaded archetypes.
+pub const PLAYER_MODEL: &str = "dogman.glb";
 
-#[derive(Resource)]
-pub struct EnemyGltf {
-    pub gltf: Handle<Gltf>,
-}
+// Where a model's state machine is unless said otherwise, e.g. "dogman.glb" ->
+// "animations/dogman.anim.ron".
+pub fn default_state_machine_path(model: &str) -> String {
+    let stem = std::path::Path::new(model)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(model);
 
-#[derive(Resource)]
-pub struct MapGltf {
-    pub gltf: Handle<Gltf>,
+    format!("animations/{}.anim.ron", stem)
 }
 
-#[derive(Resource)]
-pub struct EnemyHandle {
+// Everything needed to animate one model, built once its glTF and state machine are loaded.
+pub struct AnimatedModel {
     pub scene: Handle<Scene>,
     pub animations: Vec<AnimationNodeIndex>,
+    // Upper-body and lower-body copies of `animations`, empty when the model can't be split.
+    pub body_layer_animations: Vec<(AnimationNodeIndex, AnimationNodeIndex)>,
     pub animation_graph: Handle<AnimationGraph>,
     pub animation_name_reference: HashMap<String, usize>,
+    // `None` for attachments, which play whatever their character's state machine picks.
+    pub state_machine: Option<Handle<AnimationStateMachine>>,
 }
 
-#[derive(Resource)]
-pub struct MapHandle {
-    pub scene: Handle<Scene>,
-    pub animations: Vec<AnimationNodeIndex>,
-    pub animation_graph: Handle<AnimationGraph>,
-    pub animation_name_reference: HashMap<String, usize>,
-}
-
-pub trait MyGameHandle {
-    fn get_scene(&self) -> &Handle<Scene>;
-    fn get_animations(&self, index: usize) -> &AnimationNodeIndex;
-    fn get_animation_graph(&self) -> &Handle<AnimationGraph>;
-    fn get_animation_name_reference(&self, key: &str) -> Option<&usize>;
-    fn get_resource_type(&self) -> ResourceHandle;
-}
-
-impl MyGameHandle for CharacterHandle {
-
-    fn get_scene(&self) -> &Handle<Scene> {
-        &self.scene
+impl AnimatedModel {
+    pub fn get_state_machine<'a>(&self, state_machines: &'a Assets<AnimationStateMachine>) -> Option<&'a AnimationStateMachine> {
+        self.state_machine.as_ref().and_then(|state_machine| state_machines.get(state_machine))
     }
 
-    fn get_animations(&self, index: usize) -> &AnimationNodeIndex {
-        &self.animations[index]
+    pub fn get_animations(&self, index: usize) -> Option<&AnimationNodeIndex> {
+        self.animations.get(index)
     }
 
-    fn get_animation_graph(&self) -> &Handle<AnimationGraph> {
-        &self.animation_graph
+    pub fn get_animation_name_reference(&self, key: &str) -> Option<&usize> {
+        self.animation_name_reference.get(key)
     }
 
-    fn get_animation_name_reference(&self, key: &str) -> Option<&usize> {
-        self.animation_name_reference.get(key)
+    pub fn get_body_layer_animations(&self, index: usize) -> Option<&(AnimationNodeIndex, AnimationNodeIndex)> {
+        self.body_layer_animations.get(index)
     }
 
-    fn get_resource_type(&self) -> ResourceHandle {
-        ResourceHandle::Character
+    pub fn get_animation_name(&self, index: usize) -> Option<&str> {
+        self.animation_name_reference
+            .iter()
+            .find(|(_, animation_index)| **animation_index == index)
+            .map(|(name, _)| name.as_str())
     }
 }
 
-impl MyGameHandle for EnemyHandle {
+#[derive(Resource, Default)]
+pub struct AnimatedModels(pub HashMap<String, AnimatedModel>);
 
-    fn get_scene(&self) -> &Handle<Scene> {
-        &self.scene
+impl AnimatedModels {
+    pub fn get(&self, id: &str) -> Option<&AnimatedModel> {
+        self.0.get(id)
     }
+}
 
-    fn get_animations(&self, index: usize) -> &AnimationNodeIndex {
-        &self.animations[index]
+pub struct AnimatedModelSource {
+    pub id: String,
+    pub gltf: Handle<Gltf>,
+    pub state_machine: Option<Handle<AnimationStateMachine>>,
+}
+
+#[derive(Resource)]
+pub struct AnimatedModelSources(pub Vec<AnimatedModelSource>);
+
+impl AnimatedModelSources {
+    // Loads the model unless it's already there. The first state machine given for a model wins.
+    pub fn add(&mut self, asset_server: &AssetServer, model: &str, state_machine_path: &str) {
+        if let Some(source) = self.0.iter().find(|source| source.id == model) {
+            let other_path = source.state_machine.as_ref().and_then(|state_machine| state_machine.path());
+
+            if other_path.is_none_or(|path| path.path().to_str() != Some(state_machine_path)) {
+                warn!("{} model: already animated by another state machine, ignoring {}", model, state_machine_path);
+            }
+
+            return;
+        }
+
+        self.0.push(AnimatedModelSource {
+            id: model.to_string(),
+            gltf: asset_server.load(model.to_string()),
+            state_machine: Some(asset_server.load(state_machine_path.to_string())),
+        });
     }
 
-    fn get_animation_graph(&self) -> &Handle<AnimationGraph> {
-        &self.animation_graph
+    // Loads an attachment model, which only needs its animation graph.
+    pub fn add_attachment(&mut self, asset_server: &AssetServer, model: &str) {
+        if self.contains(model) {
+            return;
+        }
+
+        self.0.push(AnimatedModelSource {
+            id: model.to_string(),
+            gltf: asset_server.load(model.to_string()),
+            state_machine: None,
+        });
     }
 
-    fn get_animation_name_reference(&self, key: &str) -> Option<&usize> {
-        self.animation_name_reference.get(key)
+    pub fn contains(&self, model: &str) -> bool {
+        self.0.iter().any(|source| source.id == model)
     }
 
-    fn get_resource_type(&self) -> ResourceHandle {
-        ResourceHandle::Enemy
+    pub fn remove_failed(&mut self, asset_server: &AssetServer) {
+        self.0.retain(|source| {
+            let failed = matches!(asset_server.load_state(&source.gltf), LoadState::Failed(_));
+
+            if failed {
+                warn!("{} model failed to load, its entities won't animate", source.id);
+            }
+
+            !failed
+        });
     }
 }
 
+#[derive(Resource)]
+pub struct MapGltf {
+    pub gltf: Handle<Gltf>,
+}
+
+#[derive(Resource)]
+pub struct EnemyArchetypeFolder {
+    pub folder: Handle<LoadedFolder>,
+}
+
+#[derive(Resource)]
+pub struct MapHandle {
+    pub scene: Handle<Scene>,
+    pub animations: Vec<AnimationNodeIndex>,
+    pub animation_graph: Handle<AnimationGraph>,
+    pub animation_name_reference: HashMap<String, usize>,
+}
+
 fn setup(
     mut commands: Commands, 
     asset_server: Res<AssetServer>,
@@ -135,167 +184,283 @@ fn setup(
     mut graphs: ResMut<Assets<AnimationGraph>>,
 ) {
 
-    let domgan_gltf: Handle<Gltf> = asset_server.load("dogman.glb");
-
-    let alien_gltf: Handle<Gltf> = asset_server.load("AlienEnemy.glb");
-
     let test_map_gltf: Handle<Gltf> = asset_server.load("TestMap.glb");
 
-    commands.insert_resource(DogmanGltf {
-        gltf: domgan_gltf,
-    });
-
-    commands.insert_resource(EnemyGltf {
-        gltf: alien_gltf,
-    });
+    let mut model_sources = AnimatedModelSources(Vec::new());
+    model_sources.add(&asset_server, PLAYER_MODEL, &default_state_machine_path(PLAYER_MODEL));
+    commands.insert_resource(model_sources);
 
     commands.insert_resource(MapGltf {
         gltf: test_map_gltf,
     });
 
+    commands.insert_resource(EnemyArchetypeFolder {
+        folder: asset_server.load_folder("enemies"),
+    });
+
     next_asset_loading_state.set(AssetLoadingState::Init2);
 }
 
+// The enemy archetypes folder and what got loaded from it.
+#[derive(SystemParam)]
+struct EnemyArchetypeAssets<'w> {
+    folder: Res<'w, EnemyArchetypeFolder>,
+    folders: Res<'w, Assets<LoadedFolder>>,
+    archetypes: Res<'w, Assets<EnemyArchetype>>,
+}
+
 fn wait_for_gltf_to_load(
     gltf_assets: Res<Assets<Gltf>>,
-    dogman_gltf: Res<DogmanGltf>,
-    alien_gltf: Res<EnemyGltf>,
+    mut model_sources: ResMut<AnimatedModelSources>,
     test_map_gltf: Res<MapGltf>,
+    enemy_archetype_assets: EnemyArchetypeAssets,
+    asset_server: Res<AssetServer>,
     mut next_asset_loading_state: ResMut<NextState<AssetLoadingState>>,
+    mut reported_archetype_failure: Local<bool>,
 ) {
-    let Some(_dogman_gltf) = gltf_assets.get(&dogman_gltf.gltf) else {
-        return;
-    };
+    // A broken archetype file shouldn't block the game from starting, it just won't be spawnable.
+    // This runs every frame until the models are in, the failure is only worth reporting once.
+    match asset_server.get_recursive_dependency_load_state(&enemy_archetype_assets.folder.folder) {
+        Some(RecursiveDependencyLoadState::Loaded) => {}
+        Some(RecursiveDependencyLoadState::Failed(error)) => {
+            if !*reported_archetype_failure {
+                *reported_archetype_failure = true;
+                warn!("Failed to load enemy archetypes: {}", error);
+            }
+        }
+        _ => return
+    }
+
+    // Every enemy model gets animated, new archetypes don't need code changes.
+    if let Some(folder) = enemy_archetype_assets.folders.get(&enemy_archetype_assets.folder.folder) {
+        for handle in folder.handles.iter() {
+            let Some(archetype) = handle.clone().try_typed::<EnemyArchetype>().ok().and_then(|handle| enemy_archetype_assets.archetypes.get(&handle)) else {
+                continue;
+            };
 
-    let Some(_alien_gltf) = gltf_assets.get(&alien_gltf.gltf) else {
+            model_sources.add(&asset_server, &archetype.model, &archetype.state_machine_path());
+        }
+    }
+
+    // A missing or broken model would otherwise keep the game loading forever.
+    model_sources.remove_failed(&asset_server);
+
+    if model_sources.0.iter().any(|source| gltf_assets.get(&source.gltf).is_none()) {
         return;
-    };
+    }
 
     let Some(_test_map_gltf) = gltf_assets.get(&test_map_gltf.gltf) else {
         return;
     };
 
+    // The state machines decide how the graphs get built. A broken one is reported in `parse_gltf`.
+    for state_machine in model_sources.0.iter().filter_map(|source| source.state_machine.as_ref()) {
+        if matches!(asset_server.load_state(state_machine), LoadState::NotLoaded | LoadState::Loading) {
+            return;
+        }
+    }
+
     next_asset_loading_state.set(AssetLoadingState::Loading);
 }
 
+// The assets the models' animation graphs are built from.
+#[derive(SystemParam)]
+struct AnimationGraphAssets<'w> {
+    graphs: ResMut<'w, Assets<AnimationGraph>>,
+    state_machines: Res<'w, Assets<AnimationStateMachine>>,
+    gltf_nodes: Res<'w, Assets<GltfNode>>,
+}
+
 fn parse_gltf(
     gltf_assets: Res<Assets<Gltf>>,
-    gltf_node_assets: Res<Assets<GltfNode>>,
-    dogman_gltf: Res<DogmanGltf>,
-    alien_gltf: Res<EnemyGltf>,
+    model_sources: Res<AnimatedModelSources>,
     map_gltf: Res<MapGltf>,
     mut commands: Commands,
-    //character_handle: Res<CharacterHandle>,
     asset_server: Res<AssetServer>,
-    animation_clip_resource: Res<Assets<AnimationClip>>,
-    mut graphs: ResMut<Assets<AnimationGraph>>,
+    mut animation_graph_assets: AnimationGraphAssets,
     mut next_asset_loading_state: ResMut<NextState<AssetLoadingState>>,
 ) {
+    let mut animated_models = AnimatedModels::default();
+
+    for source in model_sources.0.iter() {
+        let Some(gltf) = gltf_assets.get(&source.gltf) else {
+            return;
+        };
+
+        let state_machine = source.state_machine.as_ref().and_then(|state_machine| animation_graph_assets.state_machines.get(state_machine));
+        animated_models.0.insert(source.id.clone(), build_animated_model(source, gltf, &animation_graph_assets.gltf_nodes, &mut animation_graph_assets.graphs, state_machine, &asset_server));
+    }
 
+    commands.insert_resource(animated_models);
 
-    let dogman_scene: Handle<Scene> = asset_server.load("dogman.glb#Scene0");
 
-    let Some(dogman_gltf) = gltf_assets.get(&dogman_gltf.gltf) else {
+    let map_scene: Handle<Scene> = asset_server.load("TestMap.glb#Scene0");
+
+    let Some(map_gltf) = gltf_assets.get(&map_gltf.gltf) else {
         return;
     };
 
-    let mut clips = Vec::new();
-    let mut name_mapping = HashMap::new();
+    let mut map_clips = Vec::new();
+    let mut map_name_mapping = HashMap::new();
 
-    dogman_gltf.named_animations.iter().enumerate().for_each(|(index, animation)| {
+    map_gltf.named_animations.iter().enumerate().for_each(|(index, animation)| {
 
-        let anim_path = animation.1.path().unwrap();
-        clips.push(asset_server.load(anim_path));
+        map_clips.push(animation.1.clone());
 
-        name_mapping.insert(animation.0.to_string(), index);
+        map_name_mapping.insert(animation.0.to_string(), index);
         
     });
     
-    let (graph, node_indices) = AnimationGraph::from_clips(clips);
+    let (map_graph, map_node_indices) = AnimationGraph::from_clips(map_clips);
 
-    node_indices.iter().for_each(|node| {
+    map_node_indices.iter().for_each(|node| {
         println!("{}", node.index());
     });
 
-    let graph_handle = graphs.add(graph);
+    let map_graph_handle = animation_graph_assets.graphs.add(map_graph);
 
-    commands.insert_resource(CharacterHandle {
-        scene: dogman_scene,
-        animations: node_indices.clone(),
-        animation_graph: graph_handle.clone(),
-        animation_name_reference: name_mapping.clone(),
+    commands.insert_resource(MapHandle {
+        scene: map_scene,
+        animations: map_node_indices,
+        animation_graph: map_graph_handle,
+        animation_name_reference: map_name_mapping,
     });
 
-    //Handle alien now.
-
     
-    let alien_enemy_scene: Handle<Scene> = asset_server.load("AlienEnemy.glb#Scene0");
-
-    let Some(alien_gltf) = gltf_assets.get(&alien_gltf.gltf) else {
-        return;
-    };
-
-    let mut alien_clips = Vec::new();
-    let mut alien_name_mapping = HashMap::new();
+    next_asset_loading_state.set(AssetLoadingState::Loaded);
+}
+fn build_animated_model(
+    source: &AnimatedModelSource,
+    gltf: &Gltf,
+    gltf_node_assets: &Assets<GltfNode>,
+    graphs: &mut Assets<AnimationGraph>,
+    state_machine: Option<&AnimationStateMachine>,
+    asset_server: &AssetServer,
+) -> AnimatedModel {
+    let mut clips = Vec::new();
+    let mut name_mapping = HashMap::new();
 
-    alien_gltf.named_animations.iter().enumerate().for_each(|(index, animation)| {
+    gltf.named_animations.iter().enumerate().for_each(|(index, animation)| {
 
-        let anim_path = animation.1.path().unwrap();
-        alien_clips.push(asset_server.load(anim_path));
+        // The glTF already holds the clip handles, no need to load them again by path.
+        clips.push(animation.1.clone());
 
-        alien_name_mapping.insert(animation.0.to_string(), index);
-        
-    });
-    
-    let (alien_graph, alien_node_indices) = AnimationGraph::from_clips(alien_clips);
+        name_mapping.insert(animation.0.to_string(), index);
 
-    alien_node_indices.iter().for_each(|node| {
-        println!("{}", node.index());
     });
 
-    let alien_graph_handle = graphs.add(alien_graph);
+    let (mut graph, node_indices) = AnimationGraph::from_clips(clips.clone());
 
-    commands.insert_resource(EnemyHandle {
-        scene: alien_enemy_scene,
-        animations: alien_node_indices,
-        animation_graph: alien_graph_handle,
-        animation_name_reference: alien_name_mapping,
-    });
+    // Attachments, and models whose state machine failed to load, only play on the full body.
+    let body_layer_animations = state_machine
+        .map(|state_machine| add_body_layers(&mut graph, &clips, gltf, gltf_node_assets, state_machine))
+        .unwrap_or_default();
 
+    AnimatedModel {
+        scene: asset_server.load(GltfAssetLabel::Scene(0).from_asset(source.id.clone())),
+        animations: node_indices,
+        body_layer_animations,
+        animation_graph: graphs.add(graph),
+        animation_name_reference: name_mapping,
+        state_machine: source.state_machine.clone(),
+    }
+}
 
-    let map_scene: Handle<Scene> = asset_server.load("TestMap.glb#Scene0");
+// Attachment models are only known once something gets attached, they load while playing.
+fn register_attachment_models(
+    attachment_query: Query<&AnimatedAttachment, Added<AnimatedAttachment>>,
+    mut model_sources: ResMut<AnimatedModelSources>,
+    asset_server: Res<AssetServer>,
+) {
+    for attachment in attachment_query.iter() {
+        model_sources.add_attachment(&asset_server, &attachment.model);
+    }
+}
 
-    let Some(map_gltf) = gltf_assets.get(&map_gltf.gltf) else {
-        return;
-    };
+fn build_attachment_models(
+    gltf_assets: Res<Assets<Gltf>>,
+    gltf_node_assets: Res<Assets<GltfNode>>,
+    mut model_sources: ResMut<AnimatedModelSources>,
+    mut animated_models: ResMut<AnimatedModels>,
+    mut graphs: ResMut<Assets<AnimationGraph>>,
+    state_machines: Res<Assets<AnimationStateMachine>>,
+    asset_server: Res<AssetServer>,
+) {
+    model_sources.remove_failed(&asset_server);
 
-    let mut map_clips = Vec::new();
-    let mut map_name_mapping = HashMap::new();
+    for source in model_sources.0.iter() {
+        if animated_models.0.contains_key(&source.id) {
+            continue;
+        }
 
-    map_gltf.named_animations.iter().enumerate().for_each(|(index, animation)| {
+        let Some(gltf) = gltf_assets.get(&source.gltf) else {
+            continue;
+        };
 
-        let anim_path = animation.1.path().unwrap();
-        map_clips.push(asset_server.load(anim_path));
+        let state_machine = source.state_machine.as_ref().and_then(|state_machine| state_machines.get(state_machine));
+        let animated_model = build_animated_model(source, gltf, &gltf_node_assets, &mut graphs, state_machine, &asset_server);
+        animated_models.0.insert(source.id.clone(), animated_model);
+    }
+}
 
-        map_name_mapping.insert(animation.0.to_string(), index);
-        
-    });
-    
-    let (map_graph, map_node_indices) = AnimationGraph::from_clips(map_clips);
+// Mask groups used to play clips on only part of the body.
+const UPPER_BODY_MASK_GROUP: u32 = 0;
+const LOWER_BODY_MASK_GROUP: u32 = 1;
+
+// Adds an upper-body and a lower-body copy of every clip to the graph, split at the state
+// machine's `upper_body_bone`. Returns the (upper, lower) nodes per clip, in the same order as
+// `clips`, or nothing when the model can't be split.
+fn add_body_layers(
+    graph: &mut AnimationGraph,
+    clips: &[Handle<AnimationClip>],
+    gltf: &Gltf,
+    gltf_node_assets: &Assets<GltfNode>,
+    state_machine: &AnimationStateMachine,
+) -> Vec<(AnimationNodeIndex, AnimationNodeIndex)> {
+    let Some(upper_body_bone) = &state_machine.upper_body_bone else {
+        return Vec::new();
+    };
 
-    map_node_indices.iter().for_each(|node| {
-        println!("{}", node.index());
-    });
+    let nodes: Vec<&GltfNode> = gltf.nodes.iter().filter_map(|node| gltf_node_assets.get(node)).collect();
+    let child_indices: HashSet<usize> = nodes
+        .iter()
+        .flat_map(|node| node.children.iter().filter_map(|child| gltf_node_assets.get(child)))
+        .map(|child| child.index)
+        .collect();
+
+    // Walk down from the roots the same way the glTF loader names animation targets, everything
+    // below the bone (and the bone itself) is upper body.
+    let mut stack: Vec<(&GltfNode, Vec<Name>, bool)> = nodes
+        .iter()
+        .filter(|node| !child_indices.contains(&node.index))
+        .map(|node| (*node, Vec::new(), false))
+        .collect();
+    let mut found_bone = false;
+
+    while let Some((node, mut path, upper_body)) = stack.pop() {
+        path.push(Name::new(node.name.clone()));
+        let upper_body = upper_body || node.name == *upper_body_bone;
+        found_bone |= upper_body;
+
+        let mask_group = if upper_body { UPPER_BODY_MASK_GROUP } else { LOWER_BODY_MASK_GROUP };
+        graph.add_target_to_mask_group(AnimationTargetId::from_names(path.iter()), mask_group);
+
+        for child in node.children.iter().filter_map(|child| gltf_node_assets.get(child)) {
+            stack.push((child, path.clone(), upper_body));
+        }
+    }
 
-    let map_graph_handle = graphs.add(map_graph);
+    if !found_bone {
+        warn!("Upper body bone {} not found, attacks will play on the full body", upper_body_bone);
+        return Vec::new();
+    }
 
-    commands.insert_resource(MapHandle {
-        scene: map_scene,
-        animations: map_node_indices,
-        animation_graph: map_graph_handle,
-        animation_name_reference: map_name_mapping,
-    });
+    // A node's mask lists the groups it leaves alone.
+    let upper_body = graph.add_blend_with_mask(1 << LOWER_BODY_MASK_GROUP, 1., graph.root);
+    let lower_body = graph.add_blend_with_mask(1 << UPPER_BODY_MASK_GROUP, 1., graph.root);
 
-    
-    next_asset_loading_state.set(AssetLoadingState::Loaded);
-}
\ No newline at end of file
+    clips
+        .iter()
+        .map(|clip| (graph.add_clip(clip.clone(), 1., upper_body), graph.add_clip(clip.clone(), 1., lower_body)))
+        .collect()
+}
@@ -12,7 +12,7 @@ mod character_camera;
 use character_camera::CameraState;
 
 use crate::{
-    animation_handler::{AnimationHandler, ResourceHandle}, asset_loader::{AssetLoadingState, CharacterHandle}, combat_manager::{AttackType, CombatAction}, health_manager::Health
+    animation_handler::{foot_ik::FootIk, look_at::LookAt, root_motion::RootMotion, AnimationHandler}, asset_loader::{AnimatedModels, AssetLoadingState, PLAYER_MODEL}, combat_manager::{AttackType, CombatAction}, enemy::{attack_tokens::AttackTokens, threat::Targetable}, health_manager::Health
 };
 
 #[derive(Component)]
@@ -27,20 +27,27 @@ pub fn plugin(app: &mut App) {
         ))
         .add_systems(OnEnter(AssetLoadingState::Loaded), setup)
         .add_systems(Update, (
-            apply_controls
+            apply_controls,
+            look_at_camera_target
         ).run_if(in_state(AssetLoadingState::Loaded)));
 }
 
 pub fn setup(
     mut commands: Commands,
-    dogman: Res<CharacterHandle>
+    animated_models: Res<AnimatedModels>
 ) {
+    let Some(dogman) = animated_models.get(PLAYER_MODEL) else {
+        warn!("Player model {} isn't loaded", PLAYER_MODEL);
+        return;
+    };
 
     let id = commands.spawn((
         PlayerCharacter,
         AnimationHandler {
-            current_animation: *dogman.animation_name_reference.get("Idle").unwrap(),
-            resource_type: ResourceHandle::Character
+            current_animation: 0,
+            current_state: None,
+            upper_body_state: None,
+            model: PLAYER_MODEL.to_string()
         },
         SceneRoot(dogman.scene.clone()), 
         Transform::from_xyz(0.0, 4.0, 0.0),
@@ -48,7 +55,12 @@ pub fn setup(
         Collider::cylinder(1.5, 7.3),
         TnuaController::default(),
         TnuaAvian3dSensorShape(Collider::cylinder(1.4, 7.2)),
-        Health::new(100),
+        Health::new(100.),
+        AttackTokens::default(),
+        Targetable::default(),
+        FootIk::default(),
+        // The player model faces +z, `apply_controls` turns its -z away from the movement or target.
+        LookAt::default().with_forward(Vec3::Z),
     )).id();
 
 
@@ -57,12 +69,15 @@ pub fn setup(
 }
 
 
+type PlayerCameraFilter = (With<Camera3d>, Without<PlayerCharacter>);
+
 fn apply_controls(
     keyboard: Res<ButtonInput<KeyCode>>, 
-    mut query: Query<(&mut Transform, &mut TnuaController), With<PlayerCharacter>>,
-    camera_query: Query<&Transform, (With<Camera3d>, Without<PlayerCharacter>)>
+    mut query: Query<(&mut Transform, &mut TnuaController, &RootMotion), With<PlayerCharacter>>,
+    camera_query: Query<(&Transform, &CameraState), PlayerCameraFilter>,
+    target_query: Query<&GlobalTransform>,
 ) {
-    let Ok((mut transform, mut controller)) = query.get_single_mut() else {
+    let Ok((mut transform, mut controller, root_motion)) = query.get_single_mut() else {
         return;
     };
 
@@ -81,7 +96,7 @@ fn apply_controls(
         direction -= Vec3::X;
     }
 
-    let Ok(camera_transform) = camera_query.get_single() else {
+    let Ok((camera_transform, camera_state)) = camera_query.get_single() else {
         return;
     };
 
@@ -93,20 +108,33 @@ fn apply_controls(
 
         println!("{:?}", direction);
         
+    }
 
-        let face_direction = transform.looking_to(-direction, Dir3::Y);
+    // Locked on, the player keeps facing the target and strafes or backpedals around it.
+    let locked_on_direction = camera_state.target_entity
+        .and_then(|target_entity| target_query.get(target_entity).ok())
+        .and_then(|target_transform| (target_transform.translation() - transform.translation).with_y(0.).try_normalize());
 
-        transform.rotation = transform.rotation.slerp(face_direction.rotation, 0.9);
+    if let Some(facing) = locked_on_direction.or((direction != Vec3::ZERO).then_some(direction)) {
+        let face_direction = transform.looking_to(-facing, Dir3::Y);
 
+        transform.rotation = transform.rotation.slerp(face_direction.rotation, 0.9);
     }
     
 
     // Feed the basis every frame. Even if the player doesn't move - just use `desired_velocity:
     // Vec3::ZERO`. `TnuaController` starts without a basis, which will make the character collider
     // just fall.
+    // Attacks with root motion move the character as animated, whatever the input.
+    let desired_velocity = if root_motion.active {
+        root_motion.velocity
+    } else {
+        direction.normalize_or_zero() * 20.0
+    };
+
     controller.basis(TnuaBuiltinWalk {
         // The `desired_velocity` determines how the character will move.
-        desired_velocity: direction.normalize_or_zero() * 20.0,
+        desired_velocity,
         // The `float_height` must be greater (even if by little) from the distance between the
         // character's center and the lowest point of its collider.
         float_height: 1.5,
@@ -126,4 +154,19 @@ fn apply_controls(
             ..Default::default()
         });
     }
-}
\ No newline at end of file
+}
+
+// Turns the player's head towards the locked-on target.
+fn look_at_camera_target(
+    camera_query: Query<&CameraState>,
+    target_query: Query<&GlobalTransform>,
+    mut player_query: Query<&mut LookAt, With<PlayerCharacter>>,
+) {
+    let (Ok(camera_state), Ok(mut look_at)) = (camera_query.get_single(), player_query.get_single_mut()) else {
+        return;
+    };
+
+    look_at.target = camera_state.target_entity
+        .and_then(|target_entity| target_query.get(target_entity).ok())
+        .map(GlobalTransform::translation);
+}
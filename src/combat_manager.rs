@@ -1,31 +1,40 @@
 use std::process::Command;
+use std::{collections::HashMap, time::Duration};
 
 use avian3d::prelude::{collider, Collider, Collisions, Sensor};
 use bevy::{input::mouse::MouseButtonInput, prelude::*, state::commands};
+use bevy_health_bar3d::prelude::Percentage;
 use rand::Rng;
+use serde::Deserialize;
 
 use crate::{
+    animation_handler::notify::{emit_animation_notifies, AnimationNotify, AnimationNotifyEvent},
     asset_loader::AssetLoadingState,
-    character_controller::PlayerCharacter, health_manager::HealthModifyEvent
+    character_controller::PlayerCharacter, health_manager::{Health, HealthModifyEvent, HealthModifySource}
 };
 
 pub fn plugin(app: &mut App) {
     app
+        .init_resource::<AttackIdCounter>()
         .add_systems(Update, (
             setup,
             player_attack_trigger,
-            attack_time_system,
             npc_attack
         ).run_if(in_state(AssetLoadingState::Loaded)))
         .add_systems(PostUpdate, (
+            // Reads the notifies of this frame's animation, sent once it has played.
+            attack_time_system.after(emit_animation_notifies),
             update_combat_manager_after_attack,
             setup_attack_colliders,
         ).run_if(in_state(AssetLoadingState::Loaded)))
         .add_observer(in_attack);
 }
 
+// Put on NPCs that should be attacking, `npc_attack` picks and starts the actual attacks.
 #[derive(Component)]
-pub struct AttackMode;
+pub struct AttackMode {
+    pub target: Option<Entity>
+}
 
 
 #[derive(Component)]
@@ -36,15 +45,46 @@ pub struct AttackCollider {
 #[derive(Event)]
 struct AttackEvent {
     damage: f32,
-    attacker: Entity
+    attacker: Entity,
+    attack_id: u32
+}
+
+// Hands out a unique id to every attack that gets started, so damage can be attributed to a swing.
+#[derive(Resource, Default)]
+pub struct AttackIdCounter(u32);
+
+impl AttackIdCounter {
+    pub fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_add(1);
+        self.0
+    }
 }
 
-#[derive(Component, Debug, Clone, PartialEq, Eq)]
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
 pub enum AttackType {
     Light,
     Heavy
 }
 
+// Which part of the body the attack animation plays on. Upper-body attacks leave the legs to
+// the locomotion animation, so the character can keep running while swinging.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AttackBodyMask {
+    #[default]
+    FullBody,
+    UpperBody
+}
+
+// What moves an attack on to its next phase. With `Animation` the attack clip's HitStart, HitEnd
+// and CanCancel notifies end the windup, attack and cooldown, and the timers only end a phase
+// whose notify never comes (e.g. the model lacks the clip), so keep them a bit longer than the clip.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AttackTiming {
+    #[default]
+    Timers,
+    Animation
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub enum AttackState {
     Windup,
@@ -67,7 +107,10 @@ pub struct CombatAction {
     pub windup: f32,
     pub attack_time: f32,
     pub cooldown: f32,
-    pub damage: f32
+    pub damage: f32,
+    pub attack_id: u32,
+    pub body_mask: AttackBodyMask,
+    pub timing: AttackTiming
 }
 
 impl CombatAction {
@@ -79,9 +122,27 @@ impl CombatAction {
             windup,
             attack_time,
             cooldown,
-            damage
+            damage,
+            attack_id: 0,
+            body_mask: AttackBodyMask::FullBody,
+            timing: AttackTiming::Timers
         }
     }
+
+    pub fn with_body_mask(mut self, body_mask: AttackBodyMask) -> Self {
+        self.body_mask = body_mask;
+        self
+    }
+
+    pub fn with_timing(mut self, timing: AttackTiming) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    // From the start of the windup to the end of the cooldown.
+    pub fn duration(&self) -> f32 {
+        self.windup + self.attack_time + self.cooldown
+    }
 }
 
 #[derive(Debug)]
@@ -100,7 +161,10 @@ impl Default for WeaponStats {
                 windup: 0.2,
                 attack_time: 0.1,
                 cooldown: 0.45,
-                damage: 2.0
+                damage: 2.0,
+                attack_id: 0,
+                body_mask: AttackBodyMask::UpperBody,
+                timing: AttackTiming::Animation
             },
             heavy_attack: CombatAction {
                 attack_type: AttackType::Heavy,
@@ -109,7 +173,10 @@ impl Default for WeaponStats {
                 windup: 0.4,
                 attack_time: 0.2,
                 cooldown: 1.0,
-                damage: 4.0
+                damage: 4.0,
+                attack_id: 0,
+                body_mask: AttackBodyMask::FullBody,
+                timing: AttackTiming::Animation
             }
         }
     }
@@ -139,6 +206,154 @@ pub struct CombatManager {
     pub in_attack: bool
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AttackOption {
+    pub attack_type: AttackType,
+    pub weight: f32,
+    // Only picked while the target is within this distance range.
+    pub min_distance: f32,
+    pub max_distance: f32,
+    // Time before this attack can be picked again.
+    pub cooldown: f32,
+    // Weight multiplier while the NPC's own health is low.
+    pub weight_at_low_health: f32,
+    #[serde(skip)]
+    pub remaining_cooldown: f32,
+}
+
+impl Default for AttackOption {
+    fn default() -> Self {
+        Self {
+            attack_type: AttackType::Light,
+            weight: 1.,
+            min_distance: 0.,
+            max_distance: f32::MAX,
+            cooldown: 0.,
+            weight_at_low_health: 1.,
+            remaining_cooldown: 0.,
+        }
+    }
+}
+
+// How an NPC chooses between its attacks.
+#[derive(Component, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AttackSelection {
+    pub options: Vec<AttackOption>,
+    // Pause between the end of one swing and the start of the next, picked at random in this range.
+    pub min_swing_gap: f32,
+    pub max_swing_gap: f32,
+    // Own health fraction under which `weight_at_low_health` applies.
+    pub low_health: f32,
+    #[serde(skip)]
+    pub swing_gap_remaining: f32,
+}
+
+impl Default for AttackSelection {
+    fn default() -> Self {
+        Self {
+            options: vec![
+                AttackOption {
+                    attack_type: AttackType::Light,
+                    weight: 3.,
+                    weight_at_low_health: 2.,
+                    ..default()
+                },
+                AttackOption {
+                    attack_type: AttackType::Heavy,
+                    weight: 1.,
+                    max_distance: 3.5,
+                    cooldown: 3.,
+                    weight_at_low_health: 0.5,
+                    ..default()
+                },
+            ],
+            min_swing_gap: 0.4,
+            max_swing_gap: 1.2,
+            low_health: 0.35,
+            swing_gap_remaining: 0.,
+        }
+    }
+}
+
+impl AttackSelection {
+    fn tick(&mut self, delta: f32, in_attack: bool) {
+        for option in self.options.iter_mut() {
+            option.remaining_cooldown = (option.remaining_cooldown - delta).max(0.);
+        }
+
+        // The gap only starts counting once the previous swing is over.
+        if !in_attack {
+            self.swing_gap_remaining = (self.swing_gap_remaining - delta).max(0.);
+        }
+    }
+
+    fn pick(&self, distance: Option<f32>, health_fraction: f32) -> Option<usize> {
+        let weights: Vec<f32> = self.options.iter().map(|option| {
+            if option.remaining_cooldown > 0. {
+                return 0.;
+            }
+
+            if distance.is_some_and(|distance| distance < option.min_distance || distance > option.max_distance) {
+                return 0.;
+            }
+
+            let mut weight = option.weight;
+
+            if health_fraction < self.low_health {
+                weight *= option.weight_at_low_health;
+            }
+
+            weight.max(0.)
+        }).collect();
+
+        let total: f32 = weights.iter().sum();
+
+        if total <= 0. {
+            return None;
+        }
+
+        let mut roll = rand::rng().random_range(0.0..total);
+
+        for (index, weight) in weights.iter().enumerate() {
+            roll -= weight;
+            if roll < 0. && *weight > 0. {
+                return Some(index);
+            }
+        }
+
+        weights.iter().rposition(|weight| *weight > 0.)
+    }
+
+    // Whether an attack of this type may start now, for AI that picks its attacks itself. Attack
+    // types without an option only wait for the swing gap.
+    pub fn is_ready(&self, attack_type: AttackType) -> bool {
+        self.swing_gap_remaining <= 0.
+            && self.options
+                .iter()
+                .filter(|option| option.attack_type == attack_type)
+                .all(|option| option.remaining_cooldown <= 0.)
+    }
+
+    // Puts an attack picked outside of `pick` on cooldown.
+    pub fn attack_started(&mut self, attack_type: AttackType) {
+        for option in self.options.iter_mut().filter(|option| option.attack_type == attack_type) {
+            option.remaining_cooldown = option.cooldown;
+        }
+
+        self.start_swing_gap();
+    }
+
+    fn start_swing_gap(&mut self) {
+        self.swing_gap_remaining = if self.max_swing_gap > self.min_swing_gap {
+            rand::rng().random_range(self.min_swing_gap..self.max_swing_gap)
+        } else {
+            self.min_swing_gap
+        };
+    }
+}
+
 fn setup(
     mut commands: Commands,
     player_query: Query<Entity, Added<PlayerCharacter>>
@@ -157,10 +372,33 @@ fn setup(
     });
 }
 
+// Shared entry point for starting an attack, used by the player input and by the enemy AI.
+pub fn start_attack(
+    commands: &mut Commands,
+    entity: Entity,
+    combat_manager: &mut CombatManager,
+    attack_type: AttackType,
+    attack_id_counter: &mut AttackIdCounter
+) {
+    let mut combat_action = match attack_type {
+        AttackType::Light => combat_manager.weapon.weapon_stats.light_attack.clone(),
+        AttackType::Heavy => combat_manager.weapon.weapon_stats.heavy_attack.clone()
+    };
+
+    combat_action.attack_state = AttackState::Windup;
+    combat_action.combat_timer.timer = Timer::from_seconds(combat_action.windup, TimerMode::Once);
+    combat_action.attack_id = attack_id_counter.next();
+
+    commands.entity(entity).insert(combat_action);
+
+    combat_manager.in_attack = true;
+}
+
 fn player_attack_trigger(
     mut commands: Commands,
     mut player_query: Query<(Entity, &mut CombatManager, &mut Weapon), With<PlayerCharacter>>,
-    mut mouse_click: EventReader<MouseButtonInput>
+    mut mouse_click: EventReader<MouseButtonInput>,
+    mut attack_id_counter: ResMut<AttackIdCounter>
 ) {
     for event in mouse_click.read() {
         let Ok((player_entity, mut combat_manager, mut weapon)) = player_query.get_single_mut() else {
@@ -171,28 +409,21 @@ fn player_attack_trigger(
         println!("button event: {:?}", event.button);
         // if not currently attacking
         if !combat_manager.in_attack {
-            let mut combat_action: CombatAction;
-            match event.button {
+            let attack_type = match event.button {
                 MouseButton::Left => {
-                    
                     println!("In left branch.");
-                    combat_action = combat_manager.weapon.weapon_stats.light_attack.clone();
-
+                    AttackType::Light
                 }
                 MouseButton::Right => {
                     println!("In right branch.");
-                    combat_action = combat_manager.weapon.weapon_stats.heavy_attack.clone();
+                    AttackType::Heavy
                 }
                 _ => {
                     continue;
                 }
-            }
-            combat_action.attack_state = AttackState::Windup;
-            combat_action.combat_timer.timer = Timer::from_seconds(combat_action.windup, TimerMode::Once);
-
-            commands.entity(player_entity).insert(combat_action);
+            };
 
-            combat_manager.in_attack = true;
+            start_attack(&mut commands, player_entity, &mut combat_manager, attack_type, &mut attack_id_counter);
 
             println!("{:?}",combat_manager);
         }
@@ -204,48 +435,88 @@ fn player_attack_trigger(
 
 fn attack_time_system(
     mut combat_action_query: Query<(Entity, &mut CombatAction, Option<&AttackMode>)>,
-
+    mut notify_events: EventReader<AnimationNotifyEvent>,
     time: Res<Time>,
     mut commands: Commands
 ) {
+    let mut notifies: HashMap<Entity, Vec<AnimationNotify>> = HashMap::new();
+
+    for event in notify_events.read() {
+        notifies.entry(event.entity).or_default().push(event.notify);
+    }
+
     for (entity,  mut combat_action, attack_mode_option) in combat_action_query.iter_mut() {
-        match combat_action.attack_state {
-            AttackState::Windup => {
-                combat_action.combat_timer.timer.tick(time.delta());
-                println!("Windup");
-                if combat_action.combat_timer.timer.finished() {
+        // The notifies are used in the order they were sent, so a HitStart and HitEnd in the same
+        // frame (a short hit window or a long frame) still go through the attack phase.
+        let mut pending_notifies = notifies
+            .get(&entity)
+            .filter(|_| combat_action.timing == AttackTiming::Animation)
+            .into_iter()
+            .flatten();
+
+        // Only the phase the attack was in at the start of the frame has had the frame's time.
+        let mut delta = time.delta();
+
+        loop {
+            let phase_end = match combat_action.attack_state {
+                AttackState::Windup => {
+                    debug!("Windup");
+                    AnimationNotify::HitStart
+                }
+                AttackState::Attack => {
+                    //Emit attack event
+                    commands.trigger(AttackEvent {
+                        damage: combat_action.damage,
+                        attacker: entity,
+                        attack_id: combat_action.attack_id
+                    });
+                    //println!("Attack");
+                    AnimationNotify::HitEnd
+                }
+                AttackState::Cooldown => {
+                    debug!("Cooldown");
+                    AnimationNotify::CanCancel
+                }
+                _ => {
+                    debug!("idle");
+                    commands.entity(entity).remove::<CombatAction>();
+                    if attack_mode_option.is_some() {
+                        commands.entity(entity).remove::<AttackMode>();
+                    }
+                    break;
+                }
+            };
+
+            combat_action.combat_timer.timer.tick(delta);
+            delta = Duration::ZERO;
+
+            let timer_finished = combat_action.combat_timer.timer.finished();
+
+            // Looks for the notify that ends this phase, skipping ones meant for earlier phases. The
+            // notifies after it are left for the next phases.
+            if !pending_notifies.any(|notify| *notify == phase_end) && !timer_finished {
+                break;
+            }
+
+            match combat_action.attack_state {
+                AttackState::Windup => {
                     combat_action.attack_state = AttackState::Attack;
                     combat_action.combat_timer.timer = Timer::from_seconds(combat_action.attack_time, TimerMode::Once);
                 }
-            }
-            AttackState::Attack => {
-                //Emit attack event
-                commands.trigger(AttackEvent {
-                    damage: combat_action.damage,
-                    attacker: entity,
-                    
-                });
-                combat_action.combat_timer.timer.tick(time.delta());
-                //println!("Attack");
-                if combat_action.combat_timer.timer.finished() {
+                AttackState::Attack => {
                     combat_action.attack_state = AttackState::Cooldown;
                     combat_action.combat_timer.timer = Timer::from_seconds(combat_action.cooldown, TimerMode::Once);
                 }
-            }
-            AttackState::Cooldown => {
-                println!("Cooldown");
-                
-                combat_action.combat_timer.timer.tick(time.delta());
-                if combat_action.combat_timer.timer.finished() {
+                _ => {
+                    // Idle is left for the next frame, like the phases the timers end.
                     combat_action.attack_state = AttackState::Idle;
+                    break;
                 }
             }
-            _ => {
-                println!("idle");
-                commands.entity(entity).remove::<CombatAction>();
-                if attack_mode_option.is_some() {
-                    commands.entity(entity).remove::<AttackMode>();
-                }
+
+            // A phase the timer ended starts next frame, notifies may end the next phase right away.
+            if timer_finished {
+                break;
             }
         }
     }
@@ -267,10 +538,20 @@ fn in_attack(
         
         println!("Attacker: Attack collider - {:?}, Parent: {:?}", entity, attack_collider.parent);
         for colliding_with_hand in collisions.collisions_with_entity(entity) {
-            health_modify_event_writer.send(HealthModifyEvent {
-                amount: -trigger.event().damage as i32,
-                damaged_entity: colliding_with_hand.entity1
-            });
+            let damaged_entity = if colliding_with_hand.entity1 == entity {
+                colliding_with_hand.entity2
+            } else {
+                colliding_with_hand.entity1
+            };
+
+            health_modify_event_writer.send(HealthModifyEvent::damage(
+                damaged_entity,
+                trigger.event().damage,
+                Some(HealthModifySource {
+                    attacker: trigger.event().attacker,
+                    attack_id: trigger.event().attack_id
+                })
+            ));
             println!("COLLIDING WITH HAND: {:?}", colliding_with_hand);
         }
     }
@@ -322,33 +603,161 @@ fn setup_attack_colliders(
 
 fn npc_attack(
     mut commands: Commands,
-    mut attack_mode_query: Query<(Entity, &mut CombatManager), (With<AttackMode>, Without<PlayerCharacter>)>,
+    mut npc_query: Query<(Entity, &Transform, &mut CombatManager, &mut AttackSelection, Option<&AttackMode>, Option<&Health>), Without<PlayerCharacter>>,
+    target_query: Query<&Transform>,
+    mut attack_id_counter: ResMut<AttackIdCounter>,
+    time: Res<Time>,
 ) {
-    for (entity, mut combat_manager) in attack_mode_query.iter_mut() {
-        if !combat_manager.in_attack {
-            let mut combat_action: CombatAction;
+    for (entity, transform, mut combat_manager, mut attack_selection, attack_mode_option, health_option) in npc_query.iter_mut() {
+        attack_selection.tick(time.delta_secs(), combat_manager.in_attack);
 
-            let mut rng = rand::rng();
+        let Some(attack_mode) = attack_mode_option else {
+            continue;
+        };
 
-            let random_number: f32 = rng.random_range(0.0..1.0);
+        if combat_manager.in_attack || attack_selection.swing_gap_remaining > 0. {
+            continue;
+        }
 
+        let distance = attack_mode.target
+            .and_then(|target| target_query.get(target).ok())
+            .map(|target_transform| target_transform.translation.distance(transform.translation));
+        let health_fraction = health_option.map_or(1., |health| health.value());
 
-            if random_number < 1. {
-                println!("In left branch.");
-                combat_action = combat_manager.weapon.weapon_stats.light_attack.clone();
+        let Some(index) = attack_selection.pick(distance, health_fraction) else {
+            continue;
+        };
 
-            } else {
-                println!("In right branch.");
-                combat_action = combat_manager.weapon.weapon_stats.heavy_attack.clone();
-            }
-            combat_action.attack_state = AttackState::Windup;
-            combat_action.combat_timer.timer = Timer::from_seconds(combat_action.windup, TimerMode::Once);
+        let attack_type = attack_selection.options[index].attack_type;
+        attack_selection.options[index].remaining_cooldown = attack_selection.options[index].cooldown;
+        attack_selection.start_swing_gap();
 
-            commands.entity(entity).insert(combat_action);
+        start_attack(&mut commands, entity, &mut combat_manager, attack_type, &mut attack_id_counter);
 
-            combat_manager.in_attack = true;
+        debug!("NPC {:?} picked {:?}", entity, attack_type);
+    }
+}
 
-            println!("{:?}",combat_manager);
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[derive(Resource, Default)]
+    struct Hits(u32);
+
+    // Runs `attack_time_system` for one frame on an animation-driven attack that just started
+    // its windup, with the given notifies sent by its animation.
+    fn attack_frame(notifies: &[AnimationNotify]) -> (AttackState, u32) {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.init_resource::<Events<AnimationNotifyEvent>>();
+        world.init_resource::<Hits>();
+        world.add_observer(|_: Trigger<AttackEvent>, mut hits: ResMut<Hits>| hits.0 += 1);
+
+        let mut combat_action = CombatAction::new(AttackType::Light, 10., 10., 10., 1.).with_timing(AttackTiming::Animation);
+        combat_action.attack_state = AttackState::Windup;
+        combat_action.combat_timer.timer = Timer::from_seconds(combat_action.windup, TimerMode::Once);
+        let entity = world.spawn(combat_action).id();
+
+        for notify in notifies {
+            world.send_event(AnimationNotifyEvent { entity, notify: *notify });
         }
+
+        world.run_system_once(attack_time_system).unwrap();
+
+        let attack_state = world.get::<CombatAction>(entity).unwrap().attack_state.clone();
+        (attack_state, world.resource::<Hits>().0)
+    }
+
+    #[test]
+    fn hit_start_and_end_in_one_frame_still_hit() {
+        assert_eq!(attack_frame(&[AnimationNotify::HitStart]), (AttackState::Attack, 1));
+        assert_eq!(attack_frame(&[AnimationNotify::HitStart, AnimationNotify::HitEnd]), (AttackState::Cooldown, 1));
+        assert_eq!(
+            attack_frame(&[AnimationNotify::HitStart, AnimationNotify::HitEnd, AnimationNotify::CanCancel]),
+            (AttackState::Idle, 1)
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn notifies_only_end_their_own_phase() {
+        assert_eq!(attack_frame(&[AnimationNotify::HitEnd, AnimationNotify::CanCancel]), (AttackState::Windup, 0));
+        assert_eq!(attack_frame(&[AnimationNotify::HitEnd, AnimationNotify::HitStart]), (AttackState::Attack, 1));
+        assert_eq!(attack_frame(&[AnimationNotify::Footstep, AnimationNotify::HitStart]), (AttackState::Attack, 1));
+    }
+
+    fn selection() -> AttackSelection {
+        AttackSelection {
+            options: vec![
+                AttackOption {
+                    attack_type: AttackType::Light,
+                    weight: 1.,
+                    min_distance: 2.,
+                    weight_at_low_health: 0.,
+                    ..default()
+                },
+                AttackOption {
+                    attack_type: AttackType::Heavy,
+                    weight: 1.,
+                    max_distance: 4.,
+                    ..default()
+                },
+            ],
+            ..default()
+        }
+    }
+
+    #[test]
+    fn pick_only_attacks_in_range() {
+        let selection = selection();
+
+        for _ in 0..100 {
+            assert_eq!(selection.pick(Some(1.), 1.), Some(1));
+            assert_eq!(selection.pick(Some(10.), 1.), Some(0));
+        }
+
+        assert_eq!(selection.pick(Some(3.), 1.).map(|index| index < 2), Some(true));
+    }
+
+    #[test]
+    fn pick_skips_attacks_on_cooldown() {
+        let mut selection = selection();
+        selection.options[1].remaining_cooldown = 1.;
+
+        for _ in 0..100 {
+            assert_eq!(selection.pick(Some(3.), 1.), Some(0));
+        }
+
+        assert_eq!(selection.pick(Some(1.), 1.), None);
+
+        selection.tick(1., false);
+
+        assert_eq!(selection.pick(Some(1.), 1.), Some(1));
+    }
+
+    #[test]
+    fn pick_applies_the_low_health_weight() {
+        let selection = selection();
+
+        for _ in 0..100 {
+            assert_eq!(selection.pick(Some(3.), 0.1), Some(1));
+        }
+
+        assert_eq!(selection.pick(Some(10.), 0.1), None);
+    }
+
+    #[test]
+    fn pick_follows_the_weights() {
+        let mut selection = selection();
+        selection.options[0].weight = 3.;
+
+        let light_picks = (0..4000)
+            .filter(|_| selection.pick(None, 1.) == Some(0))
+            .count();
+
+        // 3 to 1, with plenty of room for the randomness.
+        assert!((2700..3300).contains(&light_picks), "{} light picks", light_picks);
+    }
+}
@@ -1,14 +1,31 @@
 use bevy::prelude::*;
 use avian3d::prelude::*;
+use bevy_tnua::prelude::*;
+use bevy_tnua_avian3d::TnuaAvian3dSensorShape;
 
-use crate::{animation_handler::{AnimationHandler, ResourceHandle}, asset_loader::{AssetLoadingState, EnemyHandle}, character_controller::PlayerCharacter, combat_manager::{
-    AttackMode, AttackType, CombatAction, CombatManager, Weapon, WeaponStats
+use crate::{animation_handler::{foot_ik::FootIk, look_at::LookAt, AnimationHandler}, asset_loader::AssetLoadingState, combat_manager::{
+    CombatManager, Weapon
 }, health_manager::Health};
 
+mod ai;
+pub mod archetype;
+pub mod attack_tokens;
+mod behavior_tree;
+mod boss;
+mod navigation;
+mod perception;
+mod spawner;
+pub mod threat;
+
+use archetype::{EnemyArchetypes, LoadedEnemyArchetype};
+use behavior_tree::BehaviorTreeHandle;
+use boss::{Boss, BossArena};
+use spawner::{EnemySpawner, Wave};
+
 pub fn plugin(app: &mut App) {
     app
-        .add_systems(OnEnter(AssetLoadingState::Loaded), setup)
-        .add_systems(Update, (move_alien).run_if(in_state(AssetLoadingState::Loaded)));
+        .add_plugins((ai::plugin, archetype::plugin, attack_tokens::plugin, behavior_tree::plugin, boss::plugin, navigation::plugin, perception::plugin, spawner::plugin, threat::plugin))
+        .add_systems(OnEnter(AssetLoadingState::Loaded), setup);
 }
 
 #[derive(Component)]
@@ -16,67 +33,84 @@ pub struct Enemy;
 
 pub fn setup(
     mut commands: Commands,
-    //mut meshes: ResMut<Assets<Mesh>>,
-    //mut materials: ResMut<Assets<StandardMaterial>>,
-    alien_assets: Res<EnemyHandle>
 ) {
-    let id = commands.spawn((
+    commands.spawn((
+        Name::new("Arena spawner"),
+        Transform::from_xyz(0.0, 8.0, 8.0),
+        EnemySpawner {
+            max_alive: 2,
+            patrol_waypoints: vec![
+                Vec3::new(-8.0, 0.0, 8.0),
+                Vec3::new(8.0, 0.0, 8.0),
+            ],
+            ..default()
+        }.with_waves(vec![
+            Wave::new(1),
+            Wave::new(2),
+            Wave::new(3),
+        ]),
+    ));
+
+    commands.spawn((
+        Name::new("Boss arena"),
+        Transform::from_xyz(0.0, 0.0, -40.0),
+        BossArena::new("alien_overlord", 20.0).with_boss_offset(Vec3::new(0.0, 4.0, -10.0)),
+    ));
+}
+
+pub fn spawn_enemy<'a>(
+    commands: &'a mut Commands,
+    archetypes: &EnemyArchetypes,
+    enemy_id: &str,
+    transform: Transform,
+) -> Option<EntityCommands<'a>> {
+    let Some(LoadedEnemyArchetype { archetype, scene, behavior_tree }) = archetypes.get(enemy_id) else {
+        warn!("Unknown enemy id: {}", enemy_id);
+        return None;
+    };
+
+    let mut enemy = commands.spawn((
         Enemy,
         AnimationHandler {
             current_animation: 0,
-            resource_type: ResourceHandle::Enemy
+            current_state: None,
+            upper_body_state: None,
+            model: archetype.model.clone()
         },
-        SceneRoot(alien_assets.scene.clone()),
-        Transform::from_xyz(0.0, 8.0, 8.0),
+        SceneRoot(scene.clone()),
+        transform,
         RigidBody::Dynamic,
-        Collider::cylinder(1.5, 7.3),
-        LockedAxes::ROTATION_LOCKED,
+        Collider::cylinder(archetype.collider.radius, archetype.collider.height),
+        TnuaController::default(),
+        TnuaAvian3dSensorShape(Collider::cylinder(archetype.collider.radius - 0.1, archetype.collider.height - 0.1)),
         CombatManager {
             in_attack: false,
             last_attack_cooldown: 0.0,
             weapon: Weapon {
                 weapon_entity: None,
-                weapon_stats: WeaponStats {
-                    light_attack : CombatAction::new(AttackType::Light, 0.5, 0.25, 0.55, 1.),
-                    ..default()
-                }
+                weapon_stats: archetype.weapon.weapon_stats()
             }
         },
-        Health::new(100),
-    )).id();
-
-    println!("enemy id: {:?}", id);
-}
+        Health::new(archetype.health),
+        archetype.attack_selection.clone(),
+        archetype.enemy_ai(),
+        archetype.perception(),
+        LookAt::default(),
+    ));
 
-pub fn move_alien(
-    mut commands: Commands,
-    mut query: Query<(Entity, &mut Transform), With<Enemy>>,
-    player_query: Query<&Transform, (With<PlayerCharacter>, Without<Enemy>)>,
-    time: Res<Time>,
-) {
-
-    let Ok(target) = player_query.get_single() else {
-        return;
-    };
-
-
-    for (entity, mut transform) in query.iter_mut() {
+    if let Some(behavior_tree) = behavior_tree {
+        enemy.insert(BehaviorTreeHandle(behavior_tree.clone()));
+    }
 
-        if transform.translation.distance(target.translation) < 4.0 {
-            commands.entity(entity).insert(AttackMode);
-            continue;
-        }
-        
-        let mut direction_translation = target.translation.clone();
+    if let Some(boss) = &archetype.boss {
+        enemy.insert(Boss::new(boss));
+    }
 
-        direction_translation.y = 0.0;
+    if archetype.foot_ik {
+        enemy.insert(FootIk::default());
+    }
 
-        let (yaw, _pitch, _roll) = transform
-            .looking_at(direction_translation, Vec3::Y)
-            .rotation.to_euler(EulerRot::YXZ);
+    debug!("enemy id: {:?}, archetype: {}", enemy.id(), archetype.id);
 
-        transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, 0., 0.);
-        let forward = transform.forward();
-        transform.translation += forward * 5.0 * time.delta_secs();
-    }
-}
\ No newline at end of file
+    Some(enemy)
+}
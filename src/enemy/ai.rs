@@ -0,0 +1,465 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy_health_bar3d::prelude::Percentage;
+use bevy_tnua::prelude::*;
+
+use crate::{
+    animation_handler::{look_at::LookAt, root_motion::RootMotion},
+    asset_loader::AssetLoadingState,
+    combat_manager::{AttackMode, CombatAction},
+    health_manager::{DamageImmune, Health, HealthModifyEvent}
+};
+
+use super::{
+    attack_tokens::AttackTokens,
+    behavior_tree::{run_behavior_trees, BehaviorTree},
+    navigation::{update_nav_agents, NavAgent},
+    perception::{update_perception, Perception},
+    threat::ThreatTable,
+    Enemy
+};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            update_enemy_ai_state,
+            apply_enemy_ai_state,
+            leash_behavior_tree_enemies,
+            run_behavior_trees,
+            update_nav_agents,
+            move_enemies,
+            look_at_targets
+        ).chain().after(update_perception).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EnemyAiState {
+    #[default]
+    Idle,
+    Patrol,
+    Chase,
+    // Waiting for an attack token, strafing around the target at `circle_distance`.
+    Circle,
+    Attack,
+    Retreat,
+    // Gave up on the target, heading home to heal up. Can't be damaged on the way.
+    Return
+}
+
+// How close to home counts as being back.
+const HOME_RADIUS: f32 = 1.5;
+// Returning enemies hurry home so they can't be kited along the way.
+const RETURN_SPEED_FACTOR: f32 = 1.5;
+
+// Drives the enemy with a fixed state machine. Enemies with a `BehaviorTree` only use it for
+// the movement settings and the leash, the tree decides what to do.
+#[derive(Component, Debug, Clone)]
+#[require(Perception, NavAgent, ThreatTable)]
+pub struct EnemyAi {
+    pub state: EnemyAiState,
+    // Distance at which the enemy notices the player and starts chasing.
+    pub aggro_range: f32,
+    // How far the enemy is allowed to chase away from its home before giving up.
+    pub leash_distance: f32,
+    pub attack_range: f32,
+    pub circle_distance: f32,
+    pub move_speed: f32,
+    pub patrol_waypoints: Vec<Vec3>,
+    pub current_waypoint: usize,
+    // Fraction of max health under which the enemy runs away instead of fighting.
+    pub retreat_health: f32,
+    // Where the enemy stood when the AI first ran, used for the leash.
+    pub home: Option<Vec3>,
+    // Point to turn towards while standing still, e.g. the target in between swings.
+    pub look_target: Option<Vec3>,
+}
+
+impl Default for EnemyAi {
+    fn default() -> Self {
+        Self {
+            state: EnemyAiState::Idle,
+            aggro_range: 30.,
+            leash_distance: 60.,
+            attack_range: 4.,
+            circle_distance: 9.,
+            move_speed: 5.,
+            patrol_waypoints: Vec::new(),
+            current_waypoint: 0,
+            retreat_health: 0.2,
+            home: None,
+            look_target: None,
+        }
+    }
+}
+
+// What the AI knows about the world when picking its next state.
+struct AiSenses {
+    position: Vec3,
+    // Perceived position of the target, which may be a memory when it's not visible.
+    target: Option<Vec3>,
+    target_visible: bool,
+    health_fraction: f32,
+    attack_token_available: bool,
+}
+
+impl EnemyAi {
+    fn resting_state(&self) -> EnemyAiState {
+        if self.patrol_waypoints.is_empty() {
+            EnemyAiState::Idle
+        } else {
+            EnemyAiState::Patrol
+        }
+    }
+
+    fn returning_state(&self, position: Vec3, home: Vec3) -> EnemyAiState {
+        if position.xz().distance(home.xz()) < HOME_RADIUS {
+            self.resting_state()
+        } else {
+            EnemyAiState::Return
+        }
+    }
+
+    fn next_state(&self, senses: &AiSenses) -> EnemyAiState {
+        let position = senses.position;
+        let home = self.home.unwrap_or(position);
+        let beyond_leash = position.distance(home) > self.leash_distance;
+
+        // Lost the target, e.g. it died or got away, so head back home if it came to a fight.
+        let Some(target) = senses.target else {
+            return match self.state {
+                EnemyAiState::Idle | EnemyAiState::Patrol => self.resting_state(),
+                EnemyAiState::Return => self.returning_state(position, home),
+                _ => EnemyAiState::Return
+            };
+        };
+
+        let distance = position.distance(target);
+        let low_health = senses.health_fraction < self.retreat_health;
+        let target_visible = senses.target_visible;
+
+        match self.state {
+            EnemyAiState::Idle | EnemyAiState::Patrol => {
+                if target_visible && distance < self.aggro_range && !low_health {
+                    EnemyAiState::Chase
+                } else {
+                    self.state
+                }
+            }
+            EnemyAiState::Chase => {
+                if low_health {
+                    EnemyAiState::Retreat
+                } else if beyond_leash {
+                    EnemyAiState::Return
+                } else if target_visible && !senses.attack_token_available && distance < self.circle_distance {
+                    EnemyAiState::Circle
+                } else if target_visible && distance < self.attack_range {
+                    EnemyAiState::Attack
+                } else {
+                    EnemyAiState::Chase
+                }
+            }
+            EnemyAiState::Circle => {
+                if low_health {
+                    EnemyAiState::Retreat
+                } else if beyond_leash {
+                    EnemyAiState::Return
+                } else if senses.attack_token_available || !target_visible || distance > self.circle_distance * 1.5 {
+                    EnemyAiState::Chase
+                } else {
+                    EnemyAiState::Circle
+                }
+            }
+            EnemyAiState::Attack => {
+                // Small margin so the enemy doesn't flicker between chasing and attacking.
+                if low_health {
+                    EnemyAiState::Retreat
+                } else if beyond_leash {
+                    EnemyAiState::Return
+                } else if !target_visible || distance > self.attack_range * 1.25 {
+                    EnemyAiState::Chase
+                } else {
+                    EnemyAiState::Attack
+                }
+            }
+            // Ignores the target until it's back home, whatever happens on the way.
+            EnemyAiState::Return => self.returning_state(position, home),
+            EnemyAiState::Retreat => {
+                if distance > self.leash_distance || beyond_leash {
+                    EnemyAiState::Return
+                } else {
+                    EnemyAiState::Retreat
+                }
+            }
+        }
+    }
+}
+
+type EnemyStateQueryData<'a> = (Entity, &'a Transform, &'a mut EnemyAi, &'a mut Perception, Option<&'a mut ThreatTable>, Option<&'a Health>);
+
+fn update_enemy_ai_state(
+    mut commands: Commands,
+    mut enemy_query: Query<EnemyStateQueryData, (With<Enemy>, Without<BehaviorTree>)>,
+    mut attack_tokens_query: Query<&mut AttackTokens>,
+    mut health_modify_writer: EventWriter<HealthModifyEvent>,
+) {
+    for (entity, transform, mut enemy_ai, mut perception, threat_table_option, health_option) in enemy_query.iter_mut() {
+        if enemy_ai.home.is_none() {
+            enemy_ai.home = Some(transform.translation);
+        }
+
+        let mut target_tokens = perception.target.and_then(|target| attack_tokens_query.get_mut(target).ok());
+
+        let senses = AiSenses {
+            position: transform.translation,
+            target: perception.last_known_position,
+            target_visible: perception.can_see_target,
+            health_fraction: health_option.map_or(1., |health| health.value()),
+            // Targets without tokens can be attacked by everyone at once.
+            attack_token_available: target_tokens.as_ref().is_none_or(|tokens| tokens.is_available(entity)),
+        };
+
+        let mut next_state = enemy_ai.next_state(&senses);
+
+        // Also checked while already attacking, a new target's token has to be won first.
+        if next_state == EnemyAiState::Attack {
+            if let Some(tokens) = target_tokens.as_mut() {
+                if !tokens.try_acquire(entity) {
+                    next_state = EnemyAiState::Circle;
+                }
+            }
+        } else if let Some(tokens) = target_tokens.as_mut() {
+            tokens.release(entity);
+        }
+
+        if next_state == enemy_ai.state {
+            continue;
+        }
+
+        debug!("Enemy {:?} AI: {:?} -> {:?}", entity, enemy_ai.state, next_state);
+
+        if enemy_ai.state == EnemyAiState::Attack {
+            commands.entity(entity).remove::<AttackMode>();
+        }
+
+        if next_state == EnemyAiState::Return {
+            commands.entity(entity).insert(DamageImmune);
+        }
+
+        if enemy_ai.state == EnemyAiState::Return {
+            finish_return(&mut commands, entity, &mut perception, threat_table_option, health_option, &mut health_modify_writer);
+        }
+
+        enemy_ai.state = next_state;
+    }
+}
+
+// Back home: reset as if the fight never happened.
+fn finish_return(
+    commands: &mut Commands,
+    entity: Entity,
+    perception: &mut Perception,
+    threat_table_option: Option<Mut<ThreatTable>>,
+    health_option: Option<&Health>,
+    health_modify_writer: &mut EventWriter<HealthModifyEvent>,
+) {
+    commands.entity(entity).remove::<DamageImmune>();
+
+    if let Some(health) = health_option {
+        let missing_health = health.max_health() - health.current_health();
+        if missing_health > 0. {
+            health_modify_writer.send(HealthModifyEvent::heal(entity, missing_health, None));
+        }
+    }
+
+    perception.forget();
+
+    if let Some(mut threat_table) = threat_table_option {
+        threat_table.clear();
+    }
+}
+
+type LeashQueryData<'a> = (
+    Entity,
+    &'a Transform,
+    &'a mut EnemyAi,
+    &'a mut Perception,
+    &'a mut NavAgent,
+    &'a mut BehaviorTree,
+    Option<&'a mut ThreatTable>,
+    Option<&'a Health>,
+);
+
+// The leash of enemies driven by a behavior tree, which only fight while their state is `Chase`.
+// Once they get dragged too far from home or lose their target they return home like any other
+// enemy, and the tree sits it out.
+fn leash_behavior_tree_enemies(
+    mut commands: Commands,
+    mut enemy_query: Query<LeashQueryData, With<Enemy>>,
+    mut health_modify_writer: EventWriter<HealthModifyEvent>,
+) {
+    for (entity, transform, mut enemy_ai, mut perception, mut nav_agent, mut tree, threat_table_option, health_option) in enemy_query.iter_mut() {
+        let position = transform.translation;
+        let home = *enemy_ai.home.get_or_insert(position);
+
+        let next_state = match enemy_ai.state {
+            EnemyAiState::Return => enemy_ai.returning_state(position, home),
+            EnemyAiState::Chase if perception.target.is_none() || position.distance(home) > enemy_ai.leash_distance => EnemyAiState::Return,
+            _ if perception.target.is_some() => EnemyAiState::Chase,
+            _ => enemy_ai.resting_state()
+        };
+
+        if next_state != enemy_ai.state {
+            debug!("Enemy {:?} AI: {:?} -> {:?}", entity, enemy_ai.state, next_state);
+
+            if next_state == EnemyAiState::Return {
+                commands.entity(entity).insert(DamageImmune).remove::<AttackMode>();
+            }
+
+            if enemy_ai.state == EnemyAiState::Return {
+                finish_return(&mut commands, entity, &mut perception, threat_table_option, health_option, &mut health_modify_writer);
+                tree.restart();
+            }
+
+            enemy_ai.state = next_state;
+        }
+
+        if enemy_ai.state == EnemyAiState::Return {
+            nav_agent.destination = enemy_ai.home;
+            enemy_ai.look_target = None;
+        }
+    }
+}
+
+type EnemyActionQueryData<'a> = (Entity, &'a Transform, &'a mut EnemyAi, &'a mut Perception, &'a mut NavAgent, Option<&'a AttackMode>, Option<&'a CombatAction>);
+
+fn apply_enemy_ai_state(
+    mut commands: Commands,
+    mut enemy_query: Query<EnemyActionQueryData, (With<Enemy>, Without<BehaviorTree>)>,
+) {
+    for (entity, transform, mut enemy_ai, mut perception, mut nav_agent, attack_mode_option, combat_action_option) in enemy_query.iter_mut() {
+        let target = perception.last_known_position;
+
+        enemy_ai.look_target = None;
+
+        nav_agent.destination = match enemy_ai.state {
+            EnemyAiState::Idle => {
+                enemy_ai.home.filter(|home| transform.translation.xz().distance(home.xz()) > 1.)
+            }
+            EnemyAiState::Return => enemy_ai.home,
+            EnemyAiState::Patrol => {
+                let Some(waypoint) = enemy_ai.patrol_waypoints.get(enemy_ai.current_waypoint).copied() else {
+                    continue;
+                };
+
+                if transform.translation.xz().distance(waypoint.xz()) < 1. {
+                    enemy_ai.current_waypoint = (enemy_ai.current_waypoint + 1) % enemy_ai.patrol_waypoints.len();
+                }
+
+                enemy_ai.patrol_waypoints.get(enemy_ai.current_waypoint).copied()
+            }
+            EnemyAiState::Chase => {
+                // Reached the last known position without seeing the target again, give up.
+                if target.is_some_and(|target| !perception.can_see_target && transform.translation.xz().distance(target.xz()) < 1.) {
+                    perception.forget();
+                    None
+                } else {
+                    target
+                }
+            }
+            EnemyAiState::Attack => {
+                let Some(target) = target else {
+                    continue;
+                };
+
+                // Only turn between swings, so an attack commits to its direction.
+                if combat_action_option.is_none() {
+                    enemy_ai.look_target = Some(target);
+                }
+
+                // `attack_time_system` takes AttackMode away after every swing, keep it while in range.
+                if attack_mode_option.is_none() {
+                    commands.entity(entity).insert(AttackMode {
+                        target: perception.target
+                    });
+                }
+
+                None
+            }
+            EnemyAiState::Circle => {
+                let Some(target) = target else {
+                    continue;
+                };
+
+                enemy_ai.look_target = Some(target);
+
+                // Aim a little further around the circle than where we are now, so the enemy strafes.
+                let strafe_direction = if entity.index() % 2 == 0 { 1. } else { -1. };
+                let offset = (transform.translation - target).with_y(0.).normalize_or(Vec3::X);
+                let around = Quat::from_rotation_y(strafe_direction * 0.5) * offset;
+
+                Some(target + around * enemy_ai.circle_distance)
+            }
+            EnemyAiState::Retreat => {
+                target.map(|target| transform.translation + (transform.translation - target).with_y(0.).normalize_or_zero() * enemy_ai.leash_distance)
+            }
+        };
+    }
+}
+
+// Gap kept between the bottom of the collider and the ground.
+const FLOAT_MARGIN: f32 = 0.1;
+
+type MoveEnemyQueryData<'a> = (&'a Transform, &'a Collider, &'a EnemyAi, &'a NavAgent, &'a RootMotion, &'a mut TnuaController);
+
+// Enemies walk through the same Tnua controller as the player, so they collide, climb steps
+// and respond to impulses instead of being teleported along.
+fn move_enemies(
+    mut enemy_query: Query<MoveEnemyQueryData, With<Enemy>>,
+) {
+    for (transform, collider, enemy_ai, nav_agent, root_motion, mut controller) in enemy_query.iter_mut() {
+        // Keep facing the look target when there is one, e.g. strafing around the player.
+        let desired_forward = match enemy_ai.look_target {
+            Some(look_target) => Dir3::new((look_target - transform.translation).with_y(0.)).ok(),
+            None => Dir3::new(nav_agent.direction).ok()
+        };
+
+        let move_speed = match enemy_ai.state {
+            EnemyAiState::Return => enemy_ai.move_speed * RETURN_SPEED_FACTOR,
+            _ => enemy_ai.move_speed
+        };
+
+        let desired_velocity = if root_motion.active {
+            root_motion.velocity
+        } else {
+            nav_agent.direction * move_speed
+        };
+
+        controller.basis(TnuaBuiltinWalk {
+            desired_velocity,
+            desired_forward,
+            float_height: float_height(collider),
+            ..Default::default()
+        });
+    }
+}
+
+// Tnua needs the float height to be above the distance between the center of the character and
+// the lowest point of its collider, so it follows whatever collider the enemy was given.
+fn float_height(collider: &Collider) -> f32 {
+    -collider.aabb(Vec3::ZERO, Quat::IDENTITY).min.y + FLOAT_MARGIN
+}
+
+// Enemies keep their eyes on the target they're after, so it's clear who they're targeting.
+fn look_at_targets(
+    mut enemy_query: Query<(&EnemyAi, &Perception, &mut LookAt), With<Enemy>>,
+    target_query: Query<&GlobalTransform>,
+) {
+    for (enemy_ai, perception, mut look_at) in enemy_query.iter_mut() {
+        let engaged = perception.can_see_target && enemy_ai.state != EnemyAiState::Return;
+
+        look_at.target = perception.target
+            .filter(|_| engaged)
+            .and_then(|target| target_query.get(target).ok())
+            .map(GlobalTransform::translation);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext, LoadedFolder},
+    prelude::*
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    asset_loader::{default_state_machine_path, AssetLoadingState, EnemyArchetypeFolder},
+    combat_manager::{AttackBodyMask, AttackSelection, AttackTiming, AttackType, CombatAction, WeaponStats}
+};
+
+use super::{ai::EnemyAi, behavior_tree::BehaviorTreeAsset, perception::Perception};
+
+pub fn plugin(app: &mut App) {
+    app
+        .init_asset::<EnemyArchetype>()
+        .init_asset_loader::<EnemyArchetypeLoader>()
+        .add_systems(OnEnter(AssetLoadingState::Loading), index_archetypes);
+}
+
+// Everything needed to spawn one kind of enemy, loaded from `assets/enemies/*.enemy.ron`.
+#[derive(Asset, TypePath, Deserialize, Debug, Clone)]
+pub struct EnemyArchetype {
+    pub id: String,
+    // glTF file the enemy scene is loaded from, relative to the assets folder.
+    pub model: String,
+    // Animation state machine of the model, `animations/<model file name>.anim.ron` by default.
+    #[serde(default)]
+    pub animations: Option<String>,
+    pub collider: ColliderDefinition,
+    pub health: f32,
+    pub move_speed: f32,
+    pub weapon: WeaponDefinition,
+    #[serde(default)]
+    pub attack_selection: AttackSelection,
+    #[serde(default)]
+    pub ai: AiDefinition,
+    #[serde(default)]
+    pub perception: PerceptionDefinition,
+    // Path to a `.bt.ron` behavior tree, used instead of the default AI state machine.
+    #[serde(default)]
+    pub behavior_tree: Option<String>,
+    // Makes the enemy a boss with health-threshold phases, spawned by a `BossArena`.
+    #[serde(default)]
+    pub boss: Option<BossDefinition>,
+    // Keeps the feet on uneven ground, needs `legs` in the model's animation state machine.
+    #[serde(default)]
+    pub foot_ik: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ColliderDefinition {
+    pub radius: f32,
+    pub height: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AttackDefinition {
+    pub windup: f32,
+    pub attack_time: f32,
+    pub cooldown: f32,
+    pub damage: f32,
+    #[serde(default)]
+    pub body_mask: AttackBodyMask,
+    #[serde(default)]
+    pub timing: AttackTiming,
+}
+
+impl AttackDefinition {
+    fn to_combat_action(&self, attack_type: AttackType) -> CombatAction {
+        CombatAction::new(attack_type, self.windup, self.attack_time, self.cooldown, self.damage)
+            .with_body_mask(self.body_mask)
+            .with_timing(self.timing)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WeaponDefinition {
+    pub light_attack: AttackDefinition,
+    pub heavy_attack: AttackDefinition,
+}
+
+impl WeaponDefinition {
+    pub fn weapon_stats(&self) -> WeaponStats {
+        WeaponStats {
+            light_attack: self.light_attack.to_combat_action(AttackType::Light),
+            heavy_attack: self.heavy_attack.to_combat_action(AttackType::Heavy)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BossDefinition {
+    // Shown above the screen-space health bar.
+    pub name: String,
+    #[serde(default)]
+    pub phases: Vec<BossPhaseDefinition>,
+}
+
+// Entered once the boss drops below `health_below` (fraction of max health). Anything left out
+// keeps the value from the previous phase.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BossPhaseDefinition {
+    pub health_below: f32,
+    #[serde(default)]
+    pub weapon: Option<WeaponDefinition>,
+    #[serde(default)]
+    pub move_speed: Option<f32>,
+    // Animation names to play instead of the regular ones, e.g. "Idle": "IdleEnraged".
+    #[serde(default)]
+    pub animation_set: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AiDefinition {
+    pub aggro_range: f32,
+    pub leash_distance: f32,
+    pub attack_range: f32,
+    pub circle_distance: f32,
+    pub retreat_health: f32,
+}
+
+impl Default for AiDefinition {
+    fn default() -> Self {
+        let enemy_ai = EnemyAi::default();
+
+        Self {
+            aggro_range: enemy_ai.aggro_range,
+            leash_distance: enemy_ai.leash_distance,
+            attack_range: enemy_ai.attack_range,
+            circle_distance: enemy_ai.circle_distance,
+            retreat_health: enemy_ai.retreat_health,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PerceptionDefinition {
+    pub view_distance: f32,
+    pub field_of_view_degrees: f32,
+    pub hearing_range: f32,
+    pub eye_height: f32,
+    pub memory_duration: f32,
+}
+
+impl Default for PerceptionDefinition {
+    fn default() -> Self {
+        let perception = Perception::default();
+
+        Self {
+            view_distance: perception.view_distance,
+            field_of_view_degrees: perception.field_of_view.to_degrees(),
+            hearing_range: perception.hearing_range,
+            eye_height: perception.eye_height,
+            memory_duration: perception.memory_duration,
+        }
+    }
+}
+
+impl EnemyArchetype {
+    pub fn state_machine_path(&self) -> String {
+        self.animations.clone().unwrap_or_else(|| default_state_machine_path(&self.model))
+    }
+
+    pub fn enemy_ai(&self) -> EnemyAi {
+        EnemyAi {
+            aggro_range: self.ai.aggro_range,
+            leash_distance: self.ai.leash_distance,
+            attack_range: self.ai.attack_range,
+            circle_distance: self.ai.circle_distance,
+            retreat_health: self.ai.retreat_health,
+            move_speed: self.move_speed,
+            ..default()
+        }
+    }
+
+    pub fn perception(&self) -> Perception {
+        Perception {
+            view_distance: self.perception.view_distance,
+            field_of_view: self.perception.field_of_view_degrees.to_radians(),
+            hearing_range: self.perception.hearing_range,
+            eye_height: self.perception.eye_height,
+            memory_duration: self.perception.memory_duration,
+            ..default()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct EnemyArchetypeLoader;
+
+#[derive(Debug, Error)]
+pub enum EnemyArchetypeLoaderError {
+    #[error("Could not read enemy archetype: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse enemy archetype: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+}
+
+impl AssetLoader for EnemyArchetypeLoader {
+    type Asset = EnemyArchetype;
+    type Settings = ();
+    type Error = EnemyArchetypeLoaderError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes::<EnemyArchetype>(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["enemy.ron"]
+    }
+}
+
+pub struct LoadedEnemyArchetype {
+    pub archetype: EnemyArchetype,
+    pub scene: Handle<Scene>,
+    pub behavior_tree: Option<Handle<BehaviorTreeAsset>>,
+}
+
+// All archetypes from the enemies folder, keyed by their id.
+#[derive(Resource, Default)]
+pub struct EnemyArchetypes {
+    pub archetypes: HashMap<String, LoadedEnemyArchetype>,
+}
+
+impl EnemyArchetypes {
+    pub fn get(&self, id: &str) -> Option<&LoadedEnemyArchetype> {
+        self.archetypes.get(id)
+    }
+}
+
+fn index_archetypes(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    archetype_folder: Res<EnemyArchetypeFolder>,
+    folders: Res<Assets<LoadedFolder>>,
+    archetype_assets: Res<Assets<EnemyArchetype>>,
+) {
+    let mut archetypes = EnemyArchetypes::default();
+
+    let Some(folder) = folders.get(&archetype_folder.folder) else {
+        warn!("Enemy archetype folder not loaded");
+        commands.insert_resource(archetypes);
+        return;
+    };
+
+    for handle in folder.handles.iter() {
+        // The folder may hold other files too, only look at archetypes.
+        let Some(archetype) = handle.clone().try_typed::<EnemyArchetype>().ok().and_then(|handle| archetype_assets.get(&handle)) else {
+            continue;
+        };
+
+        if archetypes.archetypes.contains_key(&archetype.id) {
+            warn!("Duplicate enemy archetype id: {}", archetype.id);
+            continue;
+        }
+
+        debug!("Loaded enemy archetype: {}", archetype.id);
+
+        archetypes.archetypes.insert(archetype.id.clone(), LoadedEnemyArchetype {
+            archetype: archetype.clone(),
+            scene: asset_server.load(GltfAssetLabel::Scene(0).from_asset(archetype.model.clone())),
+            behavior_tree: archetype.behavior_tree.as_ref().map(|path| asset_server.load(path.clone())),
+        });
+    }
+
+    commands.insert_resource(archetypes);
+}
@@ -0,0 +1,118 @@
+use bevy::prelude::*;
+
+use crate::{asset_loader::AssetLoadingState, combat_manager::CombatManager};
+
+use super::{ai::{EnemyAi, EnemyAiState}, perception::Perception};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            release_stale_attack_tokens
+        ).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Put on anything enemies attack. Only `max_attackers` enemies may hold a token and attack it
+// at the same time, the rest circle around at a distance until a token frees up.
+#[derive(Component, Debug, Clone)]
+pub struct AttackTokens {
+    pub max_attackers: usize,
+    holders: Vec<Entity>,
+}
+
+impl Default for AttackTokens {
+    fn default() -> Self {
+        Self {
+            max_attackers: 2,
+            holders: Vec::new(),
+        }
+    }
+}
+
+impl AttackTokens {
+    pub fn holds(&self, attacker: Entity) -> bool {
+        self.holders.contains(&attacker)
+    }
+
+    pub fn is_available(&self, attacker: Entity) -> bool {
+        self.holds(attacker) || self.holders.len() < self.max_attackers
+    }
+
+    pub fn try_acquire(&mut self, attacker: Entity) -> bool {
+        if self.holds(attacker) {
+            return true;
+        }
+
+        if self.holders.len() >= self.max_attackers {
+            return false;
+        }
+
+        self.holders.push(attacker);
+        true
+    }
+
+    pub fn release(&mut self, attacker: Entity) {
+        self.holders.retain(|holder| *holder != attacker);
+    }
+}
+
+// Tokens of enemies that died, stopped attacking or switched to another target without giving
+// them back. Enemies on a behavior tree never enter the attack state, they hold their token for
+// as long as their swing lasts.
+fn release_stale_attack_tokens(
+    mut tokens_query: Query<(Entity, &mut AttackTokens)>,
+    enemy_query: Query<(&EnemyAi, &Perception, &CombatManager)>,
+) {
+    for (target, mut tokens) in tokens_query.iter_mut() {
+        tokens.holders.retain(|holder| {
+            enemy_query
+                .get(*holder)
+                .is_ok_and(|(enemy_ai, perception, combat_manager)| {
+                    (enemy_ai.state == EnemyAiState::Attack || combat_manager.in_attack) && perception.target == Some(target)
+                })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_max_attackers_acquire_a_token() {
+        let mut tokens = AttackTokens::default();
+        let attackers: Vec<Entity> = (0..3).map(Entity::from_raw).collect();
+
+        assert!(tokens.try_acquire(attackers[0]));
+        assert!(tokens.try_acquire(attackers[1]));
+        assert!(!tokens.is_available(attackers[2]));
+        assert!(!tokens.try_acquire(attackers[2]));
+        assert!(!tokens.holds(attackers[2]));
+    }
+
+    #[test]
+    fn holders_keep_their_token_when_acquiring_again() {
+        let mut tokens = AttackTokens { max_attackers: 1, ..default() };
+        let attacker = Entity::from_raw(0);
+
+        assert!(tokens.try_acquire(attacker));
+        assert!(tokens.is_available(attacker));
+        assert!(tokens.try_acquire(attacker));
+        assert_eq!(tokens.holders, vec![attacker]);
+    }
+
+    #[test]
+    fn released_tokens_go_to_the_next_attacker() {
+        let mut tokens = AttackTokens { max_attackers: 1, ..default() };
+        let first = Entity::from_raw(0);
+        let second = Entity::from_raw(1);
+
+        assert!(tokens.try_acquire(first));
+        assert!(!tokens.try_acquire(second));
+
+        tokens.release(first);
+
+        assert!(!tokens.holds(first));
+        assert!(tokens.try_acquire(second));
+        assert!(!tokens.is_available(first));
+    }
+}
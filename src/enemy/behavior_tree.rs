@@ -0,0 +1,687 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext},
+    prelude::*
+};
+use bevy_health_bar3d::prelude::Percentage;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    asset_loader::AssetLoadingState,
+    combat_manager::{start_attack, AttackIdCounter, AttackSelection, AttackType, CombatManager},
+    health_manager::Health
+};
+
+use super::{ai::{EnemyAi, EnemyAiState}, attack_tokens::AttackTokens, navigation::NavAgent, perception::Perception, Enemy};
+
+pub fn plugin(app: &mut App) {
+    app
+        .init_asset::<BehaviorTreeAsset>()
+        .init_asset_loader::<BehaviorTreeLoader>()
+        .add_systems(Update, (
+            instantiate_behavior_trees
+        ).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Blackboard keys filled in by `run_behavior_trees` before every tick.
+pub const TARGET_POSITION: &str = "target_position";
+pub const TARGET_VISIBLE: &str = "target_visible";
+pub const HEALTH: &str = "health";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BehaviorStatus {
+    Success,
+    Failure,
+    Running
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum BehaviorNode {
+    // Runs children in order until one fails, resuming at the running child next tick.
+    Sequence(Vec<BehaviorNode>),
+    // Runs children in order until one doesn't fail. Starts from the top every tick, so
+    // higher priority branches can interrupt a running one.
+    Selector(Vec<BehaviorNode>),
+    Inverter(Box<BehaviorNode>),
+    // Turns a finished child into a success.
+    Succeeder(Box<BehaviorNode>),
+    // Fails for this many seconds after the child last succeeded.
+    Cooldown(f32, Box<BehaviorNode>),
+    HasTarget,
+    TargetVisible,
+    TargetWithin(f32),
+    HealthBelow(f32),
+    IsSet(String),
+    SetFlag(String, bool),
+    // Walks to the target until within the given distance.
+    MoveToTarget(f32),
+    FaceTarget,
+    LightAttack,
+    HeavyAttack,
+    Wait(f32),
+}
+
+impl BehaviorNode {
+    fn children(&self) -> &[BehaviorNode] {
+        match self {
+            BehaviorNode::Sequence(children) | BehaviorNode::Selector(children) => children,
+            BehaviorNode::Inverter(child) | BehaviorNode::Succeeder(child) | BehaviorNode::Cooldown(_, child) => {
+                std::slice::from_ref(child.as_ref())
+            }
+            _ => &[]
+        }
+    }
+
+    fn count_nodes(&self, sizes: &mut Vec<usize>) -> usize {
+        let id = sizes.len();
+        sizes.push(1);
+
+        let size = 1 + self.children().iter().map(|child| child.count_nodes(sizes)).sum::<usize>();
+        sizes[id] = size;
+        size
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BlackboardValue {
+    Bool(bool),
+    Float(f32),
+    Vec3(Vec3),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Blackboard {
+    values: HashMap<String, BlackboardValue>,
+}
+
+impl Blackboard {
+    pub fn set(&mut self, key: &str, value: BlackboardValue) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.values.remove(key);
+    }
+
+    pub fn get_bool(&self, key: &str) -> bool {
+        matches!(self.values.get(key), Some(BlackboardValue::Bool(true)))
+    }
+
+    pub fn get_float(&self, key: &str) -> Option<f32> {
+        match self.values.get(key) {
+            Some(BlackboardValue::Float(value)) => Some(*value),
+            _ => None
+        }
+    }
+
+    pub fn get_vec3(&self, key: &str) -> Option<Vec3> {
+        match self.values.get(key) {
+            Some(BlackboardValue::Vec3(value)) => Some(*value),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct NodeState {
+    running_child: Option<usize>,
+    attack_started: bool,
+    elapsed: f32,
+    last_success: Option<f32>,
+}
+
+// Everything leaf nodes are allowed to touch on the entity running the tree.
+pub struct BehaviorContext<'a, 'w, 's> {
+    pub entity: Entity,
+    pub commands: &'a mut Commands<'w, 's>,
+    pub transform: &'a Transform,
+    pub combat_manager: &'a mut CombatManager,
+    pub attack_selection: Option<&'a mut AttackSelection>,
+    // Tokens of the current target, if it hands any out.
+    pub attack_tokens: Option<&'a mut AttackTokens>,
+    pub enemy_ai: &'a mut EnemyAi,
+    pub nav_agent: &'a mut NavAgent,
+    pub attack_id_counter: &'a mut AttackIdCounter,
+    pub delta: f32,
+    pub elapsed: f32,
+}
+
+// A running instance of a behavior tree. Every entity gets its own copy so node state isn't shared.
+#[derive(Component, Debug, Clone)]
+pub struct BehaviorTree {
+    root: BehaviorNode,
+    // Subtree size of every node in pre-order, used to find the id of each child.
+    sizes: Vec<usize>,
+    states: Vec<NodeState>,
+    pub blackboard: Blackboard,
+}
+
+impl BehaviorTree {
+    pub fn new(root: BehaviorNode) -> Self {
+        let mut sizes = Vec::new();
+        root.count_nodes(&mut sizes);
+
+        Self {
+            states: vec![NodeState::default(); sizes.len()],
+            sizes,
+            root,
+            blackboard: Blackboard::default(),
+        }
+    }
+
+    pub fn tick(&mut self, context: &mut BehaviorContext) -> BehaviorStatus {
+        let mut runtime = TreeRuntime {
+            sizes: &self.sizes,
+            states: &mut self.states,
+            blackboard: &mut self.blackboard,
+        };
+
+        runtime.tick(&self.root, 0, context)
+    }
+
+    // Starts over with fresh node state and an empty blackboard, e.g. after returning home.
+    pub fn restart(&mut self) {
+        self.states.fill(NodeState::default());
+        self.blackboard = Blackboard::default();
+    }
+}
+
+struct TreeRuntime<'a> {
+    sizes: &'a [usize],
+    states: &'a mut [NodeState],
+    blackboard: &'a mut Blackboard,
+}
+
+impl TreeRuntime<'_> {
+    fn child_ids(&self, id: usize, child_count: usize) -> Vec<usize> {
+        let mut ids = Vec::with_capacity(child_count);
+        let mut next = id + 1;
+
+        for _ in 0..child_count {
+            ids.push(next);
+            next += self.sizes[next];
+        }
+
+        ids
+    }
+
+    // Forget any running state in a subtree, e.g. when a selector switched to another branch.
+    fn reset(&mut self, id: usize) {
+        // Cooldowns outlive the branch being reset.
+        for state in self.states[id..id + self.sizes[id]].iter_mut() {
+            *state = NodeState {
+                last_success: state.last_success,
+                ..default()
+            };
+        }
+    }
+
+    fn tick(&mut self, node: &BehaviorNode, id: usize, context: &mut BehaviorContext) -> BehaviorStatus {
+        let target = self.blackboard.get_vec3(TARGET_POSITION);
+
+        let status = match node {
+            BehaviorNode::Sequence(children) => {
+                let child_ids = self.child_ids(id, children.len());
+                let start = self.states[id].running_child.unwrap_or(0);
+                let mut status = BehaviorStatus::Success;
+
+                for index in start..children.len() {
+                    status = self.tick(&children[index], child_ids[index], context);
+
+                    if status == BehaviorStatus::Running {
+                        self.states[id].running_child = Some(index);
+                        return status;
+                    }
+
+                    if status == BehaviorStatus::Failure {
+                        break;
+                    }
+                }
+
+                self.states[id].running_child = None;
+                status
+            }
+            BehaviorNode::Selector(children) => {
+                let child_ids = self.child_ids(id, children.len());
+                let previous = self.states[id].running_child;
+                let mut status = BehaviorStatus::Failure;
+                let mut current = None;
+
+                for index in 0..children.len() {
+                    status = self.tick(&children[index], child_ids[index], context);
+
+                    if status != BehaviorStatus::Failure {
+                        current = Some(index);
+                        break;
+                    }
+                }
+
+                if let Some(previous) = previous.filter(|previous| Some(*previous) != current) {
+                    self.reset(child_ids[previous]);
+                }
+
+                self.states[id].running_child = current.filter(|_| status == BehaviorStatus::Running);
+                status
+            }
+            BehaviorNode::Inverter(child) => match self.tick(child, id + 1, context) {
+                BehaviorStatus::Success => BehaviorStatus::Failure,
+                BehaviorStatus::Failure => BehaviorStatus::Success,
+                BehaviorStatus::Running => BehaviorStatus::Running
+            },
+            BehaviorNode::Succeeder(child) => match self.tick(child, id + 1, context) {
+                BehaviorStatus::Running => BehaviorStatus::Running,
+                _ => BehaviorStatus::Success
+            },
+            BehaviorNode::Cooldown(seconds, child) => {
+                let cooling_down = self.states[id]
+                    .last_success
+                    .is_some_and(|last_success| context.elapsed - last_success < *seconds);
+
+                if cooling_down {
+                    BehaviorStatus::Failure
+                } else {
+                    let status = self.tick(child, id + 1, context);
+
+                    if status == BehaviorStatus::Success {
+                        self.states[id].last_success = Some(context.elapsed);
+                    }
+
+                    status
+                }
+            }
+            BehaviorNode::HasTarget => condition(target.is_some()),
+            BehaviorNode::TargetVisible => condition(self.blackboard.get_bool(TARGET_VISIBLE)),
+            BehaviorNode::TargetWithin(distance) => {
+                condition(target.is_some_and(|target| target.distance(context.transform.translation) <= *distance))
+            }
+            BehaviorNode::HealthBelow(fraction) => {
+                condition(self.blackboard.get_float(HEALTH).is_some_and(|health| health < *fraction))
+            }
+            BehaviorNode::IsSet(key) => condition(self.blackboard.get_bool(key)),
+            BehaviorNode::SetFlag(key, value) => {
+                self.blackboard.set(key, BlackboardValue::Bool(*value));
+                BehaviorStatus::Success
+            }
+            BehaviorNode::MoveToTarget(stop_distance) => match target {
+                None => BehaviorStatus::Failure,
+                Some(target) if target.distance(context.transform.translation) <= *stop_distance => BehaviorStatus::Success,
+                Some(target) => {
+                    context.nav_agent.destination = Some(target);
+                    BehaviorStatus::Running
+                }
+            },
+            BehaviorNode::FaceTarget => match target {
+                None => BehaviorStatus::Failure,
+                Some(target) => {
+                    let to_target = (target - context.transform.translation).with_y(0.).try_normalize();
+                    let forward = context.transform.forward().as_vec3().with_y(0.).normalize_or_zero();
+
+                    match to_target {
+                        // Standing on the target, there's no direction to turn to.
+                        None => BehaviorStatus::Success,
+                        Some(to_target) if forward.dot(to_target) > 10_f32.to_radians().cos() => BehaviorStatus::Success,
+                        Some(_) => {
+                            context.enemy_ai.look_target = Some(target);
+                            BehaviorStatus::Running
+                        }
+                    }
+                }
+            },
+            BehaviorNode::LightAttack => self.attack(id, AttackType::Light, context),
+            BehaviorNode::HeavyAttack => self.attack(id, AttackType::Heavy, context),
+            BehaviorNode::Wait(seconds) => {
+                self.states[id].elapsed += context.delta;
+
+                if self.states[id].elapsed >= *seconds {
+                    BehaviorStatus::Success
+                } else {
+                    BehaviorStatus::Running
+                }
+            }
+        };
+
+        if status != BehaviorStatus::Running {
+            self.reset(id);
+        }
+
+        status
+    }
+
+    // Starts the attack on the first tick and keeps running until the combat manager is done with it.
+    // Fails when the attack is on cooldown or the target has no attack token to spare.
+    fn attack(&mut self, id: usize, attack_type: AttackType, context: &mut BehaviorContext) -> BehaviorStatus {
+        if self.states[id].attack_started {
+            if context.combat_manager.in_attack {
+                return BehaviorStatus::Running;
+            }
+
+            if let Some(attack_tokens) = context.attack_tokens.as_deref_mut() {
+                attack_tokens.release(context.entity);
+            }
+
+            return BehaviorStatus::Success;
+        }
+
+        if context.combat_manager.in_attack {
+            return BehaviorStatus::Failure;
+        }
+
+        if context.attack_selection.as_deref().is_some_and(|attack_selection| !attack_selection.is_ready(attack_type)) {
+            return BehaviorStatus::Failure;
+        }
+
+        if context.attack_tokens.as_deref_mut().is_some_and(|attack_tokens| !attack_tokens.try_acquire(context.entity)) {
+            return BehaviorStatus::Failure;
+        }
+
+        if let Some(attack_selection) = context.attack_selection.as_deref_mut() {
+            attack_selection.attack_started(attack_type);
+        }
+
+        start_attack(context.commands, context.entity, context.combat_manager, attack_type, context.attack_id_counter);
+        self.states[id].attack_started = true;
+
+        BehaviorStatus::Running
+    }
+}
+
+fn condition(value: bool) -> BehaviorStatus {
+    if value {
+        BehaviorStatus::Success
+    } else {
+        BehaviorStatus::Failure
+    }
+}
+
+#[derive(Asset, TypePath, Deserialize, Debug, Clone)]
+pub struct BehaviorTreeAsset {
+    pub root: BehaviorNode,
+}
+
+#[derive(Default)]
+pub struct BehaviorTreeLoader;
+
+#[derive(Debug, Error)]
+pub enum BehaviorTreeLoaderError {
+    #[error("Could not read behavior tree: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse behavior tree: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+}
+
+impl AssetLoader for BehaviorTreeLoader {
+    type Asset = BehaviorTreeAsset;
+    type Settings = ();
+    type Error = BehaviorTreeLoaderError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes::<BehaviorTreeAsset>(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bt.ron"]
+    }
+}
+
+// Swapped for a `BehaviorTree` once the asset has loaded.
+#[derive(Component)]
+pub struct BehaviorTreeHandle(pub Handle<BehaviorTreeAsset>);
+
+fn instantiate_behavior_trees(
+    mut commands: Commands,
+    handle_query: Query<(Entity, &BehaviorTreeHandle), Without<BehaviorTree>>,
+    behavior_tree_assets: Res<Assets<BehaviorTreeAsset>>,
+) {
+    for (entity, handle) in handle_query.iter() {
+        let Some(asset) = behavior_tree_assets.get(&handle.0) else {
+            continue;
+        };
+
+        commands.entity(entity).insert(BehaviorTree::new(asset.root.clone()));
+    }
+}
+
+type BehaviorTreeQueryData<'a> = (
+    Entity,
+    &'a Transform,
+    &'a mut BehaviorTree,
+    &'a mut CombatManager,
+    Option<&'a mut AttackSelection>,
+    &'a mut EnemyAi,
+    &'a mut NavAgent,
+    &'a Perception,
+    Option<&'a Health>,
+);
+
+pub fn run_behavior_trees(
+    mut commands: Commands,
+    mut tree_query: Query<BehaviorTreeQueryData, With<Enemy>>,
+    mut attack_tokens_query: Query<&mut AttackTokens>,
+    mut attack_id_counter: ResMut<AttackIdCounter>,
+    time: Res<Time>,
+) {
+    for (entity, transform, mut tree, mut combat_manager, mut attack_selection, mut enemy_ai, mut nav_agent, perception, health_option) in tree_query.iter_mut() {
+        // Heading home, see `leash_behavior_tree_enemies`.
+        if enemy_ai.state == EnemyAiState::Return {
+            continue;
+        }
+
+        match perception.last_known_position {
+            Some(position) => tree.blackboard.set(TARGET_POSITION, BlackboardValue::Vec3(position)),
+            None => tree.blackboard.remove(TARGET_POSITION)
+        }
+        tree.blackboard.set(TARGET_VISIBLE, BlackboardValue::Bool(perception.can_see_target));
+        tree.blackboard.set(HEALTH, BlackboardValue::Float(health_option.map_or(1., |health| health.value())));
+
+        // Movement and facing only last as long as a running node keeps asking for them.
+        nav_agent.destination = None;
+        enemy_ai.look_target = None;
+
+        let mut attack_tokens = perception.target.and_then(|target| attack_tokens_query.get_mut(target).ok());
+
+        let mut context = BehaviorContext {
+            entity,
+            commands: &mut commands,
+            transform,
+            combat_manager: &mut combat_manager,
+            attack_selection: attack_selection.as_deref_mut(),
+            attack_tokens: attack_tokens.as_deref_mut(),
+            enemy_ai: &mut enemy_ai,
+            nav_agent: &mut nav_agent,
+            attack_id_counter: &mut attack_id_counter,
+            delta: time.delta_secs(),
+            elapsed: time.elapsed_secs(),
+        };
+
+        tree.tick(&mut context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::CommandQueue;
+
+    use crate::combat_manager::Weapon;
+
+    use super::*;
+
+    // Stand-in for the entity running a tree.
+    struct Agent {
+        world: World,
+        entity: Entity,
+        transform: Transform,
+        combat_manager: CombatManager,
+        attack_selection: AttackSelection,
+        attack_tokens: AttackTokens,
+        enemy_ai: EnemyAi,
+        nav_agent: NavAgent,
+        attack_id_counter: AttackIdCounter,
+        elapsed: f32,
+    }
+
+    impl Agent {
+        fn new() -> Self {
+            let mut world = World::new();
+            let entity = world.spawn_empty().id();
+
+            Self {
+                world,
+                entity,
+                transform: Transform::default(),
+                combat_manager: CombatManager {
+                    weapon: Weapon::default(),
+                    last_attack_cooldown: 0.,
+                    in_attack: false,
+                },
+                attack_selection: AttackSelection::default(),
+                attack_tokens: AttackTokens::default(),
+                enemy_ai: EnemyAi::default(),
+                nav_agent: NavAgent::default(),
+                attack_id_counter: AttackIdCounter::default(),
+                elapsed: 0.,
+            }
+        }
+
+        fn tick(&mut self, tree: &mut BehaviorTree, delta: f32) -> BehaviorStatus {
+            self.elapsed += delta;
+
+            let mut queue = CommandQueue::default();
+            let mut commands = Commands::new(&mut queue, &self.world);
+
+            let mut context = BehaviorContext {
+                entity: self.entity,
+                commands: &mut commands,
+                transform: &self.transform,
+                combat_manager: &mut self.combat_manager,
+                attack_selection: Some(&mut self.attack_selection),
+                attack_tokens: Some(&mut self.attack_tokens),
+                enemy_ai: &mut self.enemy_ai,
+                nav_agent: &mut self.nav_agent,
+                attack_id_counter: &mut self.attack_id_counter,
+                delta,
+                elapsed: self.elapsed,
+            };
+
+            tree.tick(&mut context)
+        }
+    }
+
+    fn flag(key: &str) -> BehaviorNode {
+        BehaviorNode::SetFlag(key.to_string(), true)
+    }
+
+    #[test]
+    fn sequence_resumes_at_the_running_child() {
+        let mut agent = Agent::new();
+        let mut tree = BehaviorTree::new(BehaviorNode::Sequence(vec![flag("started"), BehaviorNode::Wait(1.)]));
+
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Running);
+
+        tree.blackboard.remove("started");
+
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Success);
+        assert!(!tree.blackboard.get_bool("started"));
+    }
+
+    #[test]
+    fn selector_interrupts_and_resets_lower_priority_branches() {
+        let mut agent = Agent::new();
+        let mut tree = BehaviorTree::new(BehaviorNode::Selector(vec![
+            BehaviorNode::Sequence(vec![BehaviorNode::IsSet("alarm".to_string()), BehaviorNode::Wait(1.)]),
+            BehaviorNode::Wait(1.),
+        ]));
+
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Running);
+
+        tree.blackboard.set("alarm", BlackboardValue::Bool(true));
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Running);
+
+        // The interrupted wait starts over instead of finishing with its old progress.
+        tree.blackboard.set("alarm", BlackboardValue::Bool(false));
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Running);
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Success);
+    }
+
+    #[test]
+    fn cooldown_fails_until_it_has_passed() {
+        let mut agent = Agent::new();
+        let mut tree = BehaviorTree::new(BehaviorNode::Cooldown(2., Box::new(flag("fired"))));
+
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Success);
+        assert_eq!(agent.tick(&mut tree, 1.), BehaviorStatus::Failure);
+        assert_eq!(agent.tick(&mut tree, 1.5), BehaviorStatus::Success);
+    }
+
+    #[test]
+    fn cooldown_survives_a_branch_reset() {
+        let mut agent = Agent::new();
+        let mut tree = BehaviorTree::new(BehaviorNode::Selector(vec![
+            BehaviorNode::Sequence(vec![
+                BehaviorNode::Cooldown(5., Box::new(flag("fired"))),
+                BehaviorNode::Wait(1.),
+            ]),
+            BehaviorNode::Wait(10.),
+        ]));
+
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Running);
+        assert_eq!(agent.tick(&mut tree, 1.), BehaviorStatus::Success);
+
+        // Back to the first branch, which is still cooling down.
+        tree.blackboard.remove("fired");
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Running);
+        assert!(!tree.blackboard.get_bool("fired"));
+    }
+
+    #[test]
+    fn restart_forgets_running_nodes_cooldowns_and_the_blackboard() {
+        let mut agent = Agent::new();
+        let mut tree = BehaviorTree::new(BehaviorNode::Sequence(vec![
+            BehaviorNode::Cooldown(5., Box::new(flag("fired"))),
+            BehaviorNode::Wait(1.),
+        ]));
+
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Running);
+
+        tree.restart();
+        assert!(!tree.blackboard.get_bool("fired"));
+
+        // Starts from the top again, with the cooldown ready and the wait from scratch.
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Running);
+        assert!(tree.blackboard.get_bool("fired"));
+        assert_eq!(agent.tick(&mut tree, 0.6), BehaviorStatus::Success);
+    }
+
+    #[test]
+    fn attack_needs_a_token_and_a_ready_attack() {
+        let mut agent = Agent::new();
+        let mut tree = BehaviorTree::new(BehaviorNode::LightAttack);
+
+        agent.attack_tokens.max_attackers = 0;
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Failure);
+        assert!(!agent.combat_manager.in_attack);
+
+        agent.attack_tokens.max_attackers = 1;
+        agent.attack_selection.swing_gap_remaining = 1.;
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Failure);
+        assert!(!agent.attack_tokens.holds(agent.entity));
+
+        agent.attack_selection.swing_gap_remaining = 0.;
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Running);
+        assert!(agent.combat_manager.in_attack);
+        assert!(agent.attack_tokens.holds(agent.entity));
+        assert!(!agent.attack_selection.is_ready(AttackType::Light));
+
+        agent.combat_manager.in_attack = false;
+        assert_eq!(agent.tick(&mut tree, 0.1), BehaviorStatus::Success);
+        assert!(!agent.attack_tokens.holds(agent.entity));
+    }
+}
@@ -0,0 +1,248 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use bevy_health_bar3d::prelude::Percentage;
+
+use crate::{
+    animation_handler::AnimationSet,
+    asset_loader::AssetLoadingState,
+    character_controller::PlayerCharacter,
+    combat_manager::CombatManager,
+    health_manager::Health
+};
+
+use super::{
+    ai::EnemyAi,
+    archetype::{BossDefinition, BossPhaseDefinition, EnemyArchetypes},
+    spawn_enemy
+};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            trigger_boss_arenas,
+            release_boss_arenas,
+            update_boss_phases,
+            hide_boss_world_health_bars,
+            display_boss_health
+        ).chain().run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Number of wall segments the arena ring is built from.
+const ARENA_WALL_SEGMENTS: usize = 16;
+const ARENA_WALL_HEIGHT: f32 = 12.;
+const ARENA_WALL_THICKNESS: f32 = 1.;
+
+#[derive(Component, Debug, Clone)]
+pub struct Boss {
+    pub name: String,
+    // Sorted from the highest to the lowest health threshold.
+    pub phases: Vec<BossPhaseDefinition>,
+    // How many of `phases` have been entered, 0 while the boss still fights with its base stats.
+    pub current_phase: usize,
+}
+
+impl Boss {
+    pub fn new(definition: &BossDefinition) -> Self {
+        let mut phases = definition.phases.clone();
+        phases.sort_by(|a, b| b.health_below.total_cmp(&a.health_below));
+
+        Self {
+            name: definition.name.clone(),
+            phases,
+            current_phase: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BossArenaState {
+    #[default]
+    Waiting,
+    // The boss is alive and the player is walled in.
+    Active,
+    Cleared
+}
+
+// Spawns `boss_id` at `boss_offset` once the player comes within `radius`, then walls the arena
+// off until the boss is dead.
+#[derive(Component, Debug, Clone)]
+#[require(Transform)]
+pub struct BossArena {
+    pub boss_id: String,
+    pub radius: f32,
+    pub boss_offset: Vec3,
+    pub state: BossArenaState,
+    pub boss: Option<Entity>,
+    walls: Vec<Entity>,
+}
+
+impl BossArena {
+    pub fn new(boss_id: &str, radius: f32) -> Self {
+        Self {
+            boss_id: boss_id.to_string(),
+            radius,
+            boss_offset: Vec3::ZERO,
+            state: BossArenaState::Waiting,
+            boss: None,
+            walls: Vec::new(),
+        }
+    }
+
+    pub fn with_boss_offset(mut self, boss_offset: Vec3) -> Self {
+        self.boss_offset = boss_offset;
+        self
+    }
+}
+
+fn trigger_boss_arenas(
+    mut commands: Commands,
+    mut arena_query: Query<(&GlobalTransform, &mut BossArena)>,
+    player_query: Query<&GlobalTransform, With<PlayerCharacter>>,
+    archetypes: Res<EnemyArchetypes>,
+) {
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
+
+    for (arena_transform, mut arena) in arena_query.iter_mut() {
+        if arena.state != BossArenaState::Waiting {
+            continue;
+        }
+
+        let center = arena_transform.translation();
+        let offset = player_transform.translation() - center;
+
+        if offset.xz().length() > arena.radius {
+            continue;
+        }
+
+        let boss_position = center + arena.boss_offset;
+        let Some(boss) = spawn_enemy(&mut commands, &archetypes, &arena.boss_id, Transform::from_translation(boss_position)) else {
+            arena.state = BossArenaState::Cleared;
+            continue;
+        };
+        let boss = boss.id();
+
+        info!("Boss arena triggered, spawned {} as {:?}", arena.boss_id, boss);
+
+        let segment_angle = std::f32::consts::TAU / ARENA_WALL_SEGMENTS as f32;
+        // Long enough for neighbouring segments to overlap so there are no gaps to slip through.
+        let segment_length = 2. * arena.radius * (segment_angle / 2.).tan() + ARENA_WALL_THICKNESS;
+
+        arena.walls = (0..ARENA_WALL_SEGMENTS).map(|index| {
+            let angle = index as f32 * segment_angle;
+            let direction = Vec3::new(angle.cos(), 0., angle.sin());
+            let position = center + direction * (arena.radius + ARENA_WALL_THICKNESS / 2.) + Vec3::Y * ARENA_WALL_HEIGHT / 2.;
+
+            commands.spawn((
+                Name::new("Boss arena wall"),
+                Transform::from_translation(position).looking_to(direction, Vec3::Y),
+                RigidBody::Static,
+                Collider::cuboid(segment_length, ARENA_WALL_HEIGHT, ARENA_WALL_THICKNESS),
+            )).id()
+        }).collect();
+
+        arena.boss = Some(boss);
+        arena.state = BossArenaState::Active;
+    }
+}
+
+fn release_boss_arenas(
+    mut commands: Commands,
+    mut arena_query: Query<&mut BossArena>,
+    boss_query: Query<(), With<Boss>>,
+) {
+    for mut arena in arena_query.iter_mut() {
+        if arena.state != BossArenaState::Active {
+            continue;
+        }
+
+        if arena.boss.is_some_and(|boss| boss_query.contains(boss)) {
+            continue;
+        }
+
+        info!("Boss {} defeated, opening the arena", arena.boss_id);
+
+        for wall in arena.walls.drain(..) {
+            if let Some(wall) = commands.get_entity(wall) {
+                wall.despawn_recursive();
+            }
+        }
+
+        arena.boss = None;
+        arena.state = BossArenaState::Cleared;
+    }
+}
+
+type BossPhaseQueryData<'a> = (Entity, &'a mut Boss, &'a Health, &'a mut CombatManager, &'a mut EnemyAi, Option<&'a mut AnimationSet>);
+
+fn update_boss_phases(
+    mut commands: Commands,
+    mut boss_query: Query<BossPhaseQueryData>,
+) {
+    for (entity, mut boss, health, mut combat_manager, mut enemy_ai, mut animation_set) in boss_query.iter_mut() {
+        // A big hit can skip straight past several thresholds, apply every phase on the way.
+        while let Some(phase) = boss.phases.get(boss.current_phase).filter(|phase| health.value() < phase.health_below).cloned() {
+            debug!("Boss {} entering phase {}", boss.name, boss.current_phase + 1);
+
+            if let Some(weapon) = &phase.weapon {
+                combat_manager.weapon.weapon_stats = weapon.weapon_stats();
+            }
+
+            if let Some(move_speed) = phase.move_speed {
+                enemy_ai.move_speed = move_speed;
+            }
+
+            if !phase.animation_set.is_empty() {
+                match animation_set.as_mut() {
+                    Some(animation_set) => animation_set.0 = phase.animation_set,
+                    None => {
+                        commands.entity(entity).insert(AnimationSet(phase.animation_set));
+                    }
+                }
+            }
+
+            boss.current_phase += 1;
+        }
+    }
+}
+
+// Bosses get a bar at the top of the screen instead, so hide the floating one over their head.
+fn hide_boss_world_health_bars(
+    mut bar_query: Query<(&Name, &Parent, &mut Visibility), Added<Name>>,
+    boss_query: Query<(), With<Boss>>,
+) {
+    let bar_name = format!("{}Bar", Health::type_path());
+
+    for (name, parent, mut visibility) in bar_query.iter_mut() {
+        if name.as_str() == bar_name && boss_query.contains(parent.get()) {
+            *visibility = Visibility::Hidden;
+        }
+    }
+}
+
+fn display_boss_health(
+    mut contexts: EguiContexts,
+    boss_query: Query<(&Boss, &Health)>,
+) {
+    if boss_query.is_empty() {
+        return;
+    }
+
+    egui::Area::new(egui::Id::new("boss_health"))
+        .anchor(egui::Align2::CENTER_TOP, [0., 16.])
+        .show(contexts.ctx_mut(), |ui| {
+            for (boss, health) in boss_query.iter() {
+                ui.vertical_centered(|ui| {
+                    ui.label(egui::RichText::new(&boss.name).heading().color(egui::Color32::WHITE));
+                    ui.add(
+                        egui::ProgressBar::new(health.value())
+                            .desired_width(480.)
+                            .fill(egui::Color32::from_rgb(170, 30, 30))
+                            .text(format!("{:.0} / {:.0}", health.current_health(), health.max_health()))
+                    );
+                });
+            }
+        });
+}
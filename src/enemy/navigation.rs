@@ -0,0 +1,591 @@
+use std::{cmp::Ordering, collections::{BinaryHeap, HashMap}};
+
+use avian3d::prelude::*;
+use bevy::prelude::*;
+
+use crate::{asset_loader::AssetLoadingState, map::Map};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            build_navmesh
+        ).run_if(in_state(AssetLoadingState::Loaded).and(not(resource_exists::<NavMesh>))));
+}
+
+// Walkable surface of the map, sampled on a regular grid over the map colliders. Every cell
+// stores the height of the floor if an agent can stand there.
+#[derive(Resource, Debug)]
+pub struct NavMesh {
+    origin: Vec2,
+    cell_size: f32,
+    width: usize,
+    depth: usize,
+    cells: Vec<Option<f32>>,
+    max_step_height: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct NavMeshSettings {
+    pub cell_size: f32,
+    pub agent_radius: f32,
+    pub agent_height: f32,
+    pub max_step_height: f32,
+    // In radians.
+    pub max_slope: f32,
+}
+
+impl Default for NavMeshSettings {
+    fn default() -> Self {
+        Self {
+            cell_size: 1.,
+            agent_radius: 1.5,
+            agent_height: 7.3,
+            max_step_height: 1.,
+            max_slope: 45_f32.to_radians(),
+        }
+    }
+}
+
+type Cell = (usize, usize);
+
+#[derive(Debug, PartialEq)]
+struct OpenCell {
+    cost: f32,
+    cell: Cell,
+}
+
+impl Eq for OpenCell {}
+
+impl Ord for OpenCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so the BinaryHeap pops the cheapest cell first.
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl NavMesh {
+    fn index(&self, (x, z): Cell) -> usize {
+        z * self.width + x
+    }
+
+    fn cell_of(&self, position: Vec3) -> Option<Cell> {
+        let local = (position.xz() - self.origin) / self.cell_size;
+
+        if local.x < 0. || local.y < 0. {
+            return None;
+        }
+
+        let (x, z) = (local.x as usize, local.y as usize);
+
+        if x >= self.width || z >= self.depth {
+            return None;
+        }
+
+        Some((x, z))
+    }
+
+    fn height(&self, cell: Cell) -> Option<f32> {
+        self.cells[self.index(cell)]
+    }
+
+    fn center(&self, (x, z): Cell) -> Vec3 {
+        let height = self.height((x, z)).unwrap_or_default();
+
+        Vec3::new(
+            self.origin.x + (x as f32 + 0.5) * self.cell_size,
+            height,
+            self.origin.y + (z as f32 + 0.5) * self.cell_size,
+        )
+    }
+
+    fn can_step(&self, from: Cell, to: Cell) -> bool {
+        match (self.height(from), self.height(to)) {
+            (Some(from), Some(to)) => (from - to).abs() <= self.max_step_height,
+            _ => false
+        }
+    }
+
+    fn neighbours(&self, (x, z): Cell) -> impl Iterator<Item = (Cell, f32)> + '_ {
+        const OFFSETS: [(i32, i32); 8] = [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)];
+
+        OFFSETS.iter().filter_map(move |&(dx, dz)| {
+            let nx = x as i32 + dx;
+            let nz = z as i32 + dz;
+
+            if nx < 0 || nz < 0 || nx as usize >= self.width || nz as usize >= self.depth {
+                return None;
+            }
+
+            let next = (nx as usize, nz as usize);
+
+            if !self.can_step((x, z), next) {
+                return None;
+            }
+
+            // Don't cut corners past blocked cells.
+            if dx != 0 && dz != 0
+                && (!self.can_step((x, z), (nx as usize, z)) || !self.can_step((x, z), (x, nz as usize))) {
+                return None;
+            }
+
+            let cost = if dx != 0 && dz != 0 { std::f32::consts::SQRT_2 } else { 1. };
+
+            Some((next, cost))
+        })
+    }
+
+    // Closest walkable cell to `cell` within `max_radius` rings, for goals that ended up inside walls.
+    fn nearest_walkable(&self, cell: Cell, max_radius: usize) -> Option<Cell> {
+        if self.height(cell).is_some() {
+            return Some(cell);
+        }
+
+        for radius in 1..=max_radius as i32 {
+            let mut best: Option<(Cell, i32)> = None;
+
+            for dz in -radius..=radius {
+                for dx in -radius..=radius {
+                    if dx.abs() != radius && dz.abs() != radius {
+                        continue;
+                    }
+
+                    let nx = cell.0 as i32 + dx;
+                    let nz = cell.1 as i32 + dz;
+
+                    if nx < 0 || nz < 0 || nx as usize >= self.width || nz as usize >= self.depth {
+                        continue;
+                    }
+
+                    let candidate = (nx as usize, nz as usize);
+                    let distance = dx * dx + dz * dz;
+
+                    if self.height(candidate).is_some() && best.is_none_or(|(_, best_distance)| distance < best_distance) {
+                        best = Some((candidate, distance));
+                    }
+                }
+            }
+
+            if let Some((candidate, _)) = best {
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+
+    fn nearest_cell(&self, position: Vec3) -> Option<Cell> {
+        let local = ((position.xz() - self.origin) / self.cell_size).floor();
+        let clamped = (
+            (local.x.max(0.) as usize).min(self.width.saturating_sub(1)),
+            (local.y.max(0.) as usize).min(self.depth.saturating_sub(1)),
+        );
+
+        self.nearest_walkable(clamped, 8)
+    }
+
+    // A* over the grid cells, followed by string pulling to remove the grid staircase.
+    pub fn find_path(&self, start: Vec3, goal: Vec3) -> Option<Vec<Vec3>> {
+        let start_cell = self.nearest_cell(start)?;
+        let goal_cell = self.nearest_cell(goal)?;
+
+        let heuristic = |(x, z): Cell| {
+            let dx = (x as f32 - goal_cell.0 as f32).abs();
+            let dz = (z as f32 - goal_cell.1 as f32).abs();
+            // Octile distance.
+            dx.max(dz) + (std::f32::consts::SQRT_2 - 1.) * dx.min(dz)
+        };
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<Cell, Cell> = HashMap::new();
+        let mut cost_so_far: HashMap<Cell, f32> = HashMap::new();
+
+        open.push(OpenCell { cost: heuristic(start_cell), cell: start_cell });
+        cost_so_far.insert(start_cell, 0.);
+
+        while let Some(OpenCell { cell, .. }) = open.pop() {
+            if cell == goal_cell {
+                let mut cells = vec![cell];
+                let mut current = cell;
+
+                while let Some(&previous) = came_from.get(&current) {
+                    cells.push(previous);
+                    current = previous;
+                }
+
+                cells.reverse();
+
+                let mut path = self.smooth_path(&cells);
+
+                // Finish on the exact goal when it's reachable, rather than the cell center.
+                if self.cell_of(goal) == Some(goal_cell) {
+                    if let Some(last) = path.last_mut() {
+                        *last = goal.with_y(last.y);
+                    }
+                }
+
+                return Some(path);
+            }
+
+            let current_cost = cost_so_far[&cell];
+
+            for (next, step_cost) in self.neighbours(cell) {
+                let new_cost = current_cost + step_cost;
+
+                if cost_so_far.get(&next).is_none_or(|&cost| new_cost < cost) {
+                    cost_so_far.insert(next, new_cost);
+                    came_from.insert(next, cell);
+                    open.push(OpenCell { cost: new_cost + heuristic(next), cell: next });
+                }
+            }
+        }
+
+        None
+    }
+
+    fn smooth_path(&self, cells: &[Cell]) -> Vec<Vec3> {
+        let mut path = Vec::new();
+        let mut anchor = 0;
+
+        while anchor < cells.len() - 1 {
+            // Skip ahead to the furthest cell that can be reached in a straight line.
+            let mut furthest = anchor + 1;
+
+            for candidate in (anchor + 2..cells.len()).rev() {
+                if self.walkable_line(cells[anchor], cells[candidate]) {
+                    furthest = candidate;
+                    break;
+                }
+            }
+
+            path.push(self.center(cells[furthest]));
+            anchor = furthest;
+        }
+
+        if path.is_empty() {
+            path.push(self.center(cells[0]));
+        }
+
+        path
+    }
+
+    fn walkable_line(&self, from: Cell, to: Cell) -> bool {
+        let start = Vec2::new(from.0 as f32 + 0.5, from.1 as f32 + 0.5);
+        let end = Vec2::new(to.0 as f32 + 0.5, to.1 as f32 + 0.5);
+        let steps = (start.distance(end) * 2.).ceil() as usize;
+
+        let mut previous = from;
+
+        for step in 1..=steps {
+            let point = start.lerp(end, step as f32 / steps as f32);
+            let cell = (point.x as usize, point.y as usize);
+
+            if cell != previous {
+                if !self.can_step(previous, cell) {
+                    return false;
+                }
+                previous = cell;
+            }
+        }
+
+        true
+    }
+}
+
+// Follows paths on the NavMesh. Whoever owns the agent sets `destination`, and reads `direction`
+// back to move the entity.
+#[derive(Component, Debug)]
+pub struct NavAgent {
+    pub destination: Option<Vec3>,
+    pub path: Vec<Vec3>,
+    // Horizontal direction towards the next waypoint, zero when there's nowhere to go.
+    pub direction: Vec3,
+    pub replan_timer: Timer,
+    // Replan straight away when the destination moves further than this from the planned goal.
+    pub replan_distance: f32,
+    pub waypoint_radius: f32,
+    planned_destination: Option<Vec3>,
+}
+
+impl Default for NavAgent {
+    fn default() -> Self {
+        Self {
+            destination: None,
+            path: Vec::new(),
+            direction: Vec3::ZERO,
+            replan_timer: Timer::from_seconds(0.5, TimerMode::Repeating),
+            replan_distance: 2.,
+            waypoint_radius: 1.,
+            planned_destination: None,
+        }
+    }
+}
+
+impl NavAgent {
+    fn needs_replan(&self) -> bool {
+        match (self.destination, self.planned_destination) {
+            (Some(destination), Some(planned)) => {
+                self.replan_timer.just_finished() || destination.distance(planned) > self.replan_distance
+            }
+            (Some(_), None) => true,
+            _ => false
+        }
+    }
+}
+
+pub fn update_nav_agents(
+    mut agent_query: Query<(&Transform, &mut NavAgent)>,
+    navmesh: Option<Res<NavMesh>>,
+    time: Res<Time>,
+) {
+    for (transform, mut agent) in agent_query.iter_mut() {
+        agent.replan_timer.tick(time.delta());
+
+        let Some(destination) = agent.destination else {
+            agent.path.clear();
+            agent.planned_destination = None;
+            agent.direction = Vec3::ZERO;
+            continue;
+        };
+
+        if agent.needs_replan() {
+            // Without a navmesh (still building) just head straight for the destination.
+            agent.path = match navmesh.as_ref() {
+                Some(navmesh) => navmesh.find_path(transform.translation, destination).unwrap_or_default(),
+                None => vec![destination]
+            };
+            agent.planned_destination = Some(destination);
+        }
+
+        while let Some(waypoint) = agent.path.first() {
+            if transform.translation.xz().distance(waypoint.xz()) > agent.waypoint_radius {
+                break;
+            }
+            agent.path.remove(0);
+        }
+
+        agent.direction = match agent.path.first() {
+            Some(waypoint) => (*waypoint - transform.translation).with_y(0.).normalize_or_zero(),
+            None => Vec3::ZERO
+        };
+    }
+}
+
+fn build_navmesh(
+    mut commands: Commands,
+    map_query: Query<Entity, (With<Map>, Without<ColliderConstructorHierarchy>)>,
+    children_query: Query<&Children>,
+    aabb_query: Query<&ColliderAabb>,
+    collider_parent_query: Query<&ColliderParent>,
+    rigid_body_query: Query<&RigidBody>,
+    spatial_query: SpatialQuery,
+) {
+    let Ok(map) = map_query.get_single() else {
+        return;
+    };
+
+    // The colliders are built from the scene asynchronously, wait until they have proper bounds.
+    let bounds = children_query
+        .iter_descendants(map)
+        .filter_map(|descendant| aabb_query.get(descendant).ok())
+        .filter(|aabb| aabb.size().length_squared() > 0.)
+        .fold(None, |bounds: Option<(Vec3, Vec3)>, aabb| match bounds {
+            None => Some((aabb.min, aabb.max)),
+            Some((min, max)) => Some((min.min(aabb.min), max.max(aabb.max)))
+        });
+
+    let Some((min, max)) = bounds else {
+        return;
+    };
+
+    let settings = NavMeshSettings::default();
+
+    let is_static = |entity: Entity| {
+        collider_parent_query
+            .get(entity)
+            .and_then(|parent| rigid_body_query.get(parent.get()))
+            .is_ok_and(|rigid_body| rigid_body.is_static())
+    };
+
+    let width = ((max.x - min.x) / settings.cell_size).ceil() as usize;
+    let depth = ((max.z - min.z) / settings.cell_size).ceil() as usize;
+    let filter = SpatialQueryFilter::default();
+    let clearance = Collider::cylinder(settings.agent_radius, settings.agent_height - settings.max_step_height);
+    let min_normal_y = settings.max_slope.cos();
+
+    let mut cells = Vec::with_capacity(width * depth);
+
+    for z in 0..depth {
+        for x in 0..width {
+            let origin = Vec3::new(
+                min.x + (x as f32 + 0.5) * settings.cell_size,
+                max.y + 1.,
+                min.z + (z as f32 + 0.5) * settings.cell_size,
+            );
+
+            let Some(hit) = spatial_query.cast_ray_predicate(
+                origin,
+                Dir3::NEG_Y,
+                max.y - min.y + 2.,
+                true,
+                &filter,
+                &is_static,
+            ) else {
+                cells.push(None);
+                continue;
+            };
+
+            if hit.normal.y < min_normal_y {
+                cells.push(None);
+                continue;
+            }
+
+            let floor = origin.y - hit.distance;
+
+            // Make sure an agent fits above the floor, leaving the step height free for small bumps.
+            let clearance_center = floor + settings.max_step_height + (settings.agent_height - settings.max_step_height) / 2.;
+            let mut blocked = false;
+
+            spatial_query.shape_intersections_callback(
+                &clearance,
+                origin.with_y(clearance_center),
+                Quat::IDENTITY,
+                &filter,
+                |entity| {
+                    blocked = is_static(entity);
+                    !blocked
+                },
+            );
+
+            cells.push(if blocked { None } else { Some(floor) });
+        }
+    }
+
+    let walkable = cells.iter().filter(|cell| cell.is_some()).count();
+
+    info!("Built navmesh: {}x{} cells, {} walkable", width, depth, walkable);
+
+    commands.insert_resource(NavMesh {
+        origin: min.xz(),
+        cell_size: settings.cell_size,
+        width,
+        depth,
+        cells,
+        max_step_height: settings.max_step_height,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One row per z, one character per x: '.' is floor at height 0, a digit is floor at that
+    // height and '#' is blocked.
+    fn navmesh(rows: &[&str]) -> NavMesh {
+        let cells = rows
+            .iter()
+            .flat_map(|row| row.chars().map(|cell| match cell {
+                '#' => None,
+                '.' => Some(0.),
+                height => Some(height.to_digit(10).unwrap() as f32)
+            }))
+            .collect();
+
+        NavMesh {
+            origin: Vec2::ZERO,
+            cell_size: 1.,
+            width: rows[0].len(),
+            depth: rows.len(),
+            cells,
+            max_step_height: 1.,
+        }
+    }
+
+    fn assert_walkable(navmesh: &NavMesh, start: Vec3, path: &[Vec3]) {
+        let mut from = navmesh.cell_of(start).unwrap();
+
+        for waypoint in path {
+            let to = navmesh.cell_of(*waypoint).unwrap();
+            assert!(navmesh.walkable_line(from, to), "{:?} -> {:?} crosses a blocked cell", from, to);
+            from = to;
+        }
+    }
+
+    #[test]
+    fn straight_path_goes_directly_to_the_goal() {
+        let navmesh = navmesh(&["......"]);
+        let goal = Vec3::new(5.2, 0., 0.5);
+
+        let path = navmesh.find_path(Vec3::new(0.5, 0., 0.5), goal).unwrap();
+
+        assert_eq!(path, vec![goal]);
+    }
+
+    #[test]
+    fn smoothing_removes_the_grid_staircase() {
+        let navmesh = navmesh(&[
+            ".....",
+            ".....",
+            ".....",
+        ]);
+        let goal = Vec3::new(4.5, 0., 2.5);
+
+        let path = navmesh.find_path(Vec3::new(0.5, 0., 0.5), goal).unwrap();
+
+        assert_eq!(path, vec![goal]);
+    }
+
+    #[test]
+    fn path_goes_around_obstacles() {
+        let navmesh = navmesh(&[
+            ".....",
+            ".###.",
+            ".###.",
+            ".....",
+        ]);
+        let start = Vec3::new(0.5, 0., 1.5);
+        let goal = Vec3::new(4.5, 0., 1.5);
+
+        let path = navmesh.find_path(start, goal).unwrap();
+
+        assert!(path.len() > 1);
+        assert_eq!(path.last(), Some(&goal));
+        assert_walkable(&navmesh, start, &path);
+    }
+
+    #[test]
+    fn unreachable_goal_has_no_path() {
+        let navmesh = navmesh(&[
+            "..#..",
+            "..#..",
+            "..#..",
+        ]);
+
+        assert_eq!(navmesh.find_path(Vec3::new(0.5, 0., 0.5), Vec3::new(4.5, 0., 2.5)), None);
+    }
+
+    #[test]
+    fn steps_higher_than_the_step_height_are_rejected() {
+        let cliff = navmesh(&[
+            "..5..",
+            "..5..",
+        ]);
+        let step = navmesh(&[
+            "..1..",
+            "..1..",
+        ]);
+        let start = Vec3::new(0.5, 0., 0.5);
+        let goal = Vec3::new(4.5, 0., 0.5);
+
+        assert_eq!(cliff.find_path(start, goal), None);
+
+        let path = step.find_path(start, goal).unwrap();
+        assert_eq!(path.last(), Some(&goal));
+        assert_walkable(&step, start, &path);
+    }
+}
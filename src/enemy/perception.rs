@@ -0,0 +1,193 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+
+use crate::asset_loader::AssetLoadingState;
+
+use super::{threat::{Targetable, ThreatTable}, Enemy};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            update_perception
+        ).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+#[derive(Component, Debug, Clone)]
+pub struct Perception {
+    pub view_distance: f32,
+    // Full opening angle of the vision cone, in radians.
+    pub field_of_view: f32,
+    // Targets this close are noticed even outside of the cone, e.g. when standing right behind.
+    pub hearing_range: f32,
+    // Height of the eyes above the entity origin, where line of sight rays start.
+    pub eye_height: f32,
+    // How long the last known position is remembered after losing sight of the target.
+    pub memory_duration: f32,
+    pub target: Option<Entity>,
+    pub can_see_target: bool,
+    // Updated every frame while the target is visible, then kept as a memory.
+    pub last_known_position: Option<Vec3>,
+    pub time_since_seen: f32,
+    // Target dropped by `forget`, still to be cleared from the threat table.
+    pub(super) forgotten: Option<Entity>,
+}
+
+impl Default for Perception {
+    fn default() -> Self {
+        Self {
+            view_distance: 40.,
+            field_of_view: 120_f32.to_radians(),
+            hearing_range: 5.,
+            eye_height: 2.5,
+            memory_duration: 5.,
+            target: None,
+            can_see_target: false,
+            last_known_position: None,
+            time_since_seen: 0.,
+            forgotten: None,
+        }
+    }
+}
+
+impl Perception {
+    pub fn forget(&mut self) {
+        self.forgotten = self.target.take();
+        self.can_see_target = false;
+        self.last_known_position = None;
+    }
+
+    fn in_view_cone(&self, transform: &Transform, target: Vec3) -> bool {
+        let to_target = target - transform.translation;
+        let distance = to_target.length();
+
+        if distance <= self.hearing_range {
+            return true;
+        }
+
+        if distance > self.view_distance {
+            return false;
+        }
+
+        let flat_forward = transform.forward().as_vec3().with_y(0.).normalize_or_zero();
+        let flat_to_target = to_target.with_y(0.).normalize_or_zero();
+
+        flat_forward.angle_between(flat_to_target) <= self.field_of_view / 2.
+    }
+}
+
+fn has_line_of_sight(
+    spatial_query: &SpatialQuery,
+    sensor_query: &Query<(), With<Sensor>>,
+    viewer: Entity,
+    eye: Vec3,
+    target_entity: Entity,
+    target: Vec3,
+) -> bool {
+    let Ok(direction) = Dir3::new(target - eye) else {
+        return true;
+    };
+
+    // Ignore the viewer's own colliders and any sensors (attack colliders, triggers).
+    let hit = spatial_query.cast_ray_predicate(
+        eye,
+        direction,
+        eye.distance(target),
+        true,
+        &SpatialQueryFilter::from_excluded_entities([viewer]),
+        &|entity| !sensor_query.contains(entity),
+    );
+
+    match hit {
+        None => true,
+        Some(hit) => hit.entity == target_entity
+    }
+}
+
+pub fn update_perception(
+    mut enemy_query: Query<(Entity, &Transform, &mut Perception, Option<&mut ThreatTable>), With<Enemy>>,
+    target_query: Query<(Entity, &Transform, &Targetable), Without<Enemy>>,
+    sensor_query: Query<(), With<Sensor>>,
+    spatial_query: SpatialQuery,
+    time: Res<Time>,
+) {
+    for (entity, transform, mut perception, threat_table) in enemy_query.iter_mut() {
+        let eye = transform.translation + Vec3::Y * perception.eye_height;
+
+        let visible: Vec<(Entity, Vec3, &Targetable)> = target_query
+            .iter()
+            .filter(|(_, target_transform, _)| perception.in_view_cone(transform, target_transform.translation))
+            .filter(|(target_entity, target_transform, _)| has_line_of_sight(
+                &spatial_query,
+                &sensor_query,
+                entity,
+                eye,
+                *target_entity,
+                target_transform.translation,
+            ))
+            .map(|(target_entity, target_transform, targetable)| (target_entity, target_transform.translation, targetable))
+            .collect();
+
+        let target = match threat_table {
+            Some(mut threat_table) => {
+                for (target_entity, target_position, targetable) in visible.iter() {
+                    let distance = target_position.distance(transform.translation);
+                    threat_table.add_sight_threat(*target_entity, distance, targetable.threat_multiplier, time.delta_secs());
+                }
+
+                threat_table.update(time.delta_secs(), |target_entity| target_query.contains(target_entity));
+
+                // Forgotten targets stay forgotten until they are seen or hit us again.
+                if let Some(forgotten) = perception.forgotten.take() {
+                    threat_table.remove(forgotten);
+                }
+
+                threat_table.top_target(perception.target.filter(|target| target_query.contains(*target)))
+            }
+            // Without a threat table just go for the closest visible target.
+            None => visible
+                .iter()
+                .min_by(|(_, a, _), (_, b, _)| {
+                    a.distance_squared(transform.translation)
+                        .total_cmp(&b.distance_squared(transform.translation))
+                })
+                .map(|(target_entity, _, _)| *target_entity)
+                .or(perception.target.filter(|target| target_query.contains(*target)))
+        };
+
+        if target != perception.target {
+            debug!("Enemy {:?} switched target from {:?} to {:?}", entity, perception.target, target);
+            perception.target = target;
+            perception.can_see_target = false;
+            // Whoever drew our attention, e.g. by hitting us, gives their position away.
+            perception.last_known_position = target
+                .and_then(|target| target_query.get(target).ok())
+                .map(|(_, target_transform, _)| target_transform.translation);
+            perception.time_since_seen = 0.;
+        }
+
+        let seen = target.and_then(|target| visible.iter().find(|(target_entity, _, _)| *target_entity == target));
+
+        match seen {
+            Some((target_entity, target_position, _)) => {
+                if !perception.can_see_target {
+                    debug!("Enemy {:?} spotted {:?}", entity, target_entity);
+                }
+                perception.can_see_target = true;
+                perception.last_known_position = Some(*target_position);
+                perception.time_since_seen = 0.;
+            }
+            None => {
+                perception.can_see_target = false;
+
+                if perception.last_known_position.is_some() {
+                    perception.time_since_seen += time.delta_secs();
+
+                    if perception.time_since_seen > perception.memory_duration {
+                        debug!("Enemy {:?} lost track of {:?}", entity, perception.target);
+                        perception.forget();
+                    }
+                }
+            }
+        }
+    }
+}
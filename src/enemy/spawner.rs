@@ -0,0 +1,270 @@
+use bevy::prelude::*;
+use rand::Rng;
+
+use crate::asset_loader::AssetLoadingState;
+
+use super::{ai::{EnemyAi, EnemyAiState}, archetype::EnemyArchetypes, spawn_enemy};
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_event::<WaveStartedEvent>()
+        .add_event::<WaveClearedEvent>()
+        .add_systems(Update, (
+            attach_map_spawners,
+            run_spawners,
+            log_waves
+        ).chain().run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Nodes in the map glTF whose name starts with this get a default spawner, e.g. "EnemySpawner.001".
+const MAP_SPAWNER_PREFIX: &str = "EnemySpawner";
+
+#[derive(Debug, Clone)]
+pub struct SpawnEntry {
+    pub enemy_id: String,
+    pub weight: f32,
+}
+
+impl SpawnEntry {
+    pub fn new(enemy_id: &str, weight: f32) -> Self {
+        Self {
+            enemy_id: enemy_id.to_string(),
+            weight
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Wave {
+    pub enemy_count: u32,
+    // Overrides the spawner's spawn table for this wave when not empty.
+    pub spawn_table: Vec<SpawnEntry>,
+}
+
+impl Wave {
+    pub fn new(enemy_count: u32) -> Self {
+        Self {
+            enemy_count,
+            spawn_table: Vec::new()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnerState {
+    #[default]
+    Waiting,
+    Spawning,
+    Finished
+}
+
+// Spawns enemies around itself. Without waves it keeps `max_alive` enemies around forever,
+// with waves it spawns each wave in turn and waits for it to be cleared before the next one.
+#[derive(Component, Debug, Clone)]
+#[require(Transform)]
+pub struct EnemySpawner {
+    pub spawn_table: Vec<SpawnEntry>,
+    pub max_alive: usize,
+    pub spawn_cooldown: Timer,
+    pub waves: Vec<Wave>,
+    // Pause before the first wave and between waves.
+    pub wave_delay: Timer,
+    pub spawn_radius: f32,
+    pub patrol_waypoints: Vec<Vec3>,
+    pub state: SpawnerState,
+    pub current_wave: usize,
+    pub spawned_in_wave: u32,
+}
+
+impl Default for EnemySpawner {
+    fn default() -> Self {
+        Self {
+            spawn_table: vec![SpawnEntry::new("alien", 1.)],
+            max_alive: 3,
+            spawn_cooldown: Timer::from_seconds(2., TimerMode::Once),
+            waves: Vec::new(),
+            wave_delay: Timer::from_seconds(3., TimerMode::Once),
+            spawn_radius: 4.,
+            patrol_waypoints: Vec::new(),
+            state: SpawnerState::Waiting,
+            current_wave: 0,
+            spawned_in_wave: 0,
+        }
+    }
+}
+
+impl EnemySpawner {
+    pub fn with_waves(mut self, waves: Vec<Wave>) -> Self {
+        self.waves = waves;
+        self
+    }
+
+    fn spawn_table(&self) -> &[SpawnEntry] {
+        match self.waves.get(self.current_wave) {
+            Some(wave) if !wave.spawn_table.is_empty() => &wave.spawn_table,
+            _ => &self.spawn_table
+        }
+    }
+
+    fn pick_enemy(&self) -> Option<&str> {
+        let table = self.spawn_table();
+        let total: f32 = table.iter().map(|entry| entry.weight.max(0.)).sum();
+
+        if total <= 0. {
+            return None;
+        }
+
+        let mut roll = rand::rng().random_range(0.0..total);
+
+        for entry in table {
+            roll -= entry.weight.max(0.);
+            if roll < 0. {
+                return Some(&entry.enemy_id);
+            }
+        }
+
+        table.last().map(|entry| entry.enemy_id.as_str())
+    }
+}
+
+// Put on every enemy a spawner creates, so it can count how many of its enemies are still alive.
+#[derive(Component, Debug)]
+pub struct SpawnedBy(pub Entity);
+
+#[derive(Event, Debug)]
+pub struct WaveStartedEvent {
+    pub spawner: Entity,
+    pub wave: usize,
+}
+
+#[derive(Event, Debug)]
+pub struct WaveClearedEvent {
+    pub spawner: Entity,
+    pub wave: usize,
+    pub last_wave: bool,
+}
+
+type NewSpawnerNameFilter = (Added<Name>, Without<EnemySpawner>);
+
+fn attach_map_spawners(
+    mut commands: Commands,
+    name_query: Query<(Entity, &Name), NewSpawnerNameFilter>,
+) {
+    for (entity, name) in name_query.iter() {
+        if name.as_str().starts_with(MAP_SPAWNER_PREFIX) {
+            debug!("Found map spawner: {}", name);
+            commands.entity(entity).insert(EnemySpawner::default());
+        }
+    }
+}
+
+fn run_spawners(
+    mut commands: Commands,
+    mut spawner_query: Query<(Entity, &GlobalTransform, &mut EnemySpawner)>,
+    spawned_query: Query<&SpawnedBy>,
+    mut wave_started_writer: EventWriter<WaveStartedEvent>,
+    mut wave_cleared_writer: EventWriter<WaveClearedEvent>,
+    archetypes: Res<EnemyArchetypes>,
+    time: Res<Time>,
+) {
+    for (spawner_entity, spawner_transform, mut spawner) in spawner_query.iter_mut() {
+        let alive = spawned_query.iter().filter(|spawned_by| spawned_by.0 == spawner_entity).count();
+
+        spawner.spawn_cooldown.tick(time.delta());
+
+        let wanted = match spawner.state {
+            SpawnerState::Finished => continue,
+            SpawnerState::Waiting => {
+                if spawner.waves.is_empty() {
+                    spawner.state = SpawnerState::Spawning;
+                    continue;
+                }
+
+                if !spawner.wave_delay.tick(time.delta()).finished() {
+                    continue;
+                }
+
+                wave_started_writer.send(WaveStartedEvent {
+                    spawner: spawner_entity,
+                    wave: spawner.current_wave,
+                });
+
+                spawner.state = SpawnerState::Spawning;
+                spawner.spawned_in_wave = 0;
+                continue;
+            }
+            SpawnerState::Spawning => {
+                match spawner.waves.get(spawner.current_wave) {
+                    None => true,
+                    Some(wave) => {
+                        let remaining = wave.enemy_count.saturating_sub(spawner.spawned_in_wave);
+
+                        if remaining == 0 && alive == 0 {
+                            let last_wave = spawner.current_wave + 1 >= spawner.waves.len();
+
+                            wave_cleared_writer.send(WaveClearedEvent {
+                                spawner: spawner_entity,
+                                wave: spawner.current_wave,
+                                last_wave,
+                            });
+
+                            spawner.current_wave += 1;
+                            spawner.wave_delay.reset();
+                            spawner.state = if last_wave { SpawnerState::Finished } else { SpawnerState::Waiting };
+                            continue;
+                        }
+
+                        remaining > 0
+                    }
+                }
+            }
+        };
+
+        if !wanted || alive >= spawner.max_alive || !spawner.spawn_cooldown.finished() {
+            continue;
+        }
+
+        let Some(enemy_id) = spawner.pick_enemy().map(str::to_string) else {
+            continue;
+        };
+
+        let mut rng = rand::rng();
+        let angle = rng.random_range(0.0..std::f32::consts::TAU);
+        let distance = rng.random_range(0.0..=spawner.spawn_radius);
+        let position = spawner_transform.translation() + Vec3::new(angle.cos(), 0., angle.sin()) * distance;
+
+        let Some(mut enemy) = spawn_enemy(&mut commands, &archetypes, &enemy_id, Transform::from_translation(position)) else {
+            continue;
+        };
+
+        enemy.insert(SpawnedBy(spawner_entity));
+
+        if !spawner.patrol_waypoints.is_empty() {
+            let patrol_waypoints = spawner.patrol_waypoints.clone();
+            enemy.entry::<EnemyAi>().and_modify(|mut enemy_ai| {
+                enemy_ai.patrol_waypoints = patrol_waypoints;
+                enemy_ai.state = EnemyAiState::Patrol;
+            });
+        }
+
+        spawner.spawned_in_wave += 1;
+        spawner.spawn_cooldown.reset();
+    }
+}
+
+fn log_waves(
+    mut wave_started_reader: EventReader<WaveStartedEvent>,
+    mut wave_cleared_reader: EventReader<WaveClearedEvent>,
+) {
+    for event in wave_started_reader.read() {
+        info!("Spawner {:?} starting wave {}", event.spawner, event.wave + 1);
+    }
+
+    for event in wave_cleared_reader.read() {
+        if event.last_wave {
+            info!("Spawner {:?} cleared its last wave ({})", event.spawner, event.wave + 1);
+        } else {
+            info!("Spawner {:?} cleared wave {}", event.spawner, event.wave + 1);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use bevy::prelude::*;
+
+use crate::{
+    asset_loader::AssetLoadingState,
+    health_manager::{HealthModifyEvent, HealthModifyKind}
+};
+
+use super::perception::update_perception;
+
+pub fn plugin(app: &mut App) {
+    app
+        .add_systems(Update, (
+            add_damage_threat
+        ).before(update_perception).run_if(in_state(AssetLoadingState::Loaded)));
+}
+
+// Anything enemies may pick as a target: the player, companions, decoys.
+#[derive(Component, Debug, Clone)]
+pub struct Targetable {
+    // Scales all threat this entity generates, decoys can use a high value to pull enemies off others.
+    pub threat_multiplier: f32,
+}
+
+impl Default for Targetable {
+    fn default() -> Self {
+        Self {
+            threat_multiplier: 1.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ThreatEntry {
+    entity: Entity,
+    threat: f32,
+}
+
+// How much each potential target has drawn an enemy's attention. Damage dealt to the enemy adds
+// threat once, being seen nearby adds it over time, and all of it slowly decays.
+#[derive(Component, Debug, Clone)]
+pub struct ThreatTable {
+    entries: Vec<ThreatEntry>,
+    // Threat per point of damage taken.
+    pub damage_threat: f32,
+    // Threat per second from any visible target.
+    pub sight_threat: f32,
+    // Extra threat per second from a visible target right next to the enemy, falling off to
+    // nothing at `proximity_range`.
+    pub proximity_threat: f32,
+    pub proximity_range: f32,
+    // Threat lost per second by every entry.
+    pub decay: f32,
+    // A new target needs this much more threat than the current one before the enemy switches,
+    // so it doesn't flip back and forth between two targets with similar threat.
+    pub switch_factor: f32,
+}
+
+impl Default for ThreatTable {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            damage_threat: 2.,
+            sight_threat: 1.,
+            proximity_threat: 4.,
+            proximity_range: 20.,
+            decay: 0.5,
+            switch_factor: 1.2,
+        }
+    }
+}
+
+impl ThreatTable {
+    pub fn threat(&self, entity: Entity) -> f32 {
+        self.entries
+            .iter()
+            .find(|entry| entry.entity == entity)
+            .map_or(0., |entry| entry.threat)
+    }
+
+    pub fn add_threat(&mut self, entity: Entity, threat: f32) {
+        match self.entries.iter_mut().find(|entry| entry.entity == entity) {
+            Some(entry) => entry.threat += threat,
+            None => self.entries.push(ThreatEntry { entity, threat })
+        }
+    }
+
+    pub fn add_sight_threat(&mut self, entity: Entity, distance: f32, threat_multiplier: f32, delta: f32) {
+        let closeness = 1. - (distance / self.proximity_range).clamp(0., 1.);
+        let threat = self.sight_threat + self.proximity_threat * closeness;
+        self.add_threat(entity, threat * threat_multiplier * delta);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        self.entries.retain(|entry| entry.entity != entity);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Decays every entry and drops the ones that ran out or whose entity no longer qualifies.
+    pub fn update(&mut self, delta: f32, mut keep: impl FnMut(Entity) -> bool) {
+        let decay = self.decay * delta;
+
+        self.entries.retain_mut(|entry| {
+            entry.threat -= decay;
+            entry.threat > 0. && keep(entry.entity)
+        });
+    }
+
+    // The entity with the most threat, sticking with `current` unless it's clearly outdone. Once
+    // all threat has decayed the current target is kept, perception memory decides when to let go.
+    pub fn top_target(&self, current: Option<Entity>) -> Option<Entity> {
+        let Some(top) = self.entries.iter().max_by(|a, b| a.threat.total_cmp(&b.threat)) else {
+            return current;
+        };
+
+        match current {
+            Some(current) if self.threat(current) * self.switch_factor >= top.threat => Some(current),
+            _ => Some(top.entity)
+        }
+    }
+}
+
+fn add_damage_threat(
+    mut health_modify_events: EventReader<HealthModifyEvent>,
+    mut threat_query: Query<&mut ThreatTable>,
+    targetable_query: Query<&Targetable>,
+) {
+    for event in health_modify_events.read() {
+        if event.kind != HealthModifyKind::Damage {
+            continue;
+        }
+
+        let Some(source) = event.source else {
+            continue;
+        };
+
+        let Ok(targetable) = targetable_query.get(source.attacker) else {
+            continue;
+        };
+
+        let Ok(mut threat_table) = threat_query.get_mut(event.target_entity) else {
+            continue;
+        };
+
+        let threat = event.amount * threat_table.damage_threat * targetable.threat_multiplier;
+        threat_table.add_threat(source.attacker, threat);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threat_decays_and_runs_out() {
+        let mut threat_table = ThreatTable::default();
+        let target = Entity::from_raw(0);
+
+        threat_table.add_threat(target, 1.);
+        threat_table.update(1., |_| true);
+
+        assert_eq!(threat_table.threat(target), 0.5);
+
+        threat_table.update(1., |_| true);
+
+        assert_eq!(threat_table.threat(target), 0.);
+        assert_eq!(threat_table.top_target(None), None);
+    }
+
+    #[test]
+    fn update_drops_targets_that_no_longer_qualify() {
+        let mut threat_table = ThreatTable::default();
+        let kept = Entity::from_raw(0);
+        let dropped = Entity::from_raw(1);
+
+        threat_table.add_threat(kept, 5.);
+        threat_table.add_threat(dropped, 10.);
+        threat_table.update(0., |entity| entity == kept);
+
+        assert_eq!(threat_table.threat(dropped), 0.);
+        assert_eq!(threat_table.top_target(None), Some(kept));
+    }
+
+    #[test]
+    fn closer_targets_draw_more_sight_threat() {
+        let mut threat_table = ThreatTable::default();
+        let near = Entity::from_raw(0);
+        let far = Entity::from_raw(1);
+
+        threat_table.add_sight_threat(near, 2., 1., 1.);
+        threat_table.add_sight_threat(far, 30., 1., 1.);
+
+        assert!(threat_table.threat(near) > threat_table.threat(far));
+        assert_eq!(threat_table.threat(far), threat_table.sight_threat);
+        assert_eq!(threat_table.top_target(None), Some(near));
+    }
+
+    #[test]
+    fn top_target_only_switches_when_clearly_outdone() {
+        let mut threat_table = ThreatTable::default();
+        let current = Entity::from_raw(0);
+        let other = Entity::from_raw(1);
+
+        threat_table.add_threat(current, 10.);
+        threat_table.add_threat(other, 11.);
+
+        assert_eq!(threat_table.top_target(Some(current)), Some(current));
+
+        threat_table.add_threat(other, 2.);
+
+        assert_eq!(threat_table.top_target(Some(current)), Some(other));
+    }
+
+    #[test]
+    fn top_target_keeps_the_current_target_without_threat() {
+        let threat_table = ThreatTable::default();
+        let current = Entity::from_raw(0);
+
+        assert_eq!(threat_table.top_target(Some(current)), Some(current));
+    }
+}
@@ -19,8 +19,8 @@ pub fn plugin(app: &mut App) {
 #[derive(Component, Reflect)]
 #[require(BarSettings::<Health>(health_bar_default))]
 pub struct Health {
-    current_health: i32,
-    max_health: i32
+    current_health: f32,
+    max_health: f32
 }
 
 fn health_bar_default() -> BarSettings<Health> {
@@ -33,30 +33,90 @@ fn health_bar_default() -> BarSettings<Health> {
 }
 
 impl Health {
-    pub fn new(max_health: i32) -> Self {
+    pub fn new(max_health: f32) -> Self {
         Self {
             current_health: max_health,
             max_health
         }
     }
+
+    pub fn current_health(&self) -> f32 {
+        self.current_health
+    }
+
+    pub fn max_health(&self) -> f32 {
+        self.max_health
+    }
 }
 
 impl Percentage for Health {
     fn value(&self) -> f32 {
-        self.current_health as f32 / self.max_health as f32
+        self.current_health / self.max_health
     }
 }
 
+// Damage against entities with this is ignored, e.g. enemies evading back to their home.
+#[derive(Component, Debug)]
+pub struct DamageImmune;
+
 // Health-based events
 
-#[derive(Event)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthModifyKind {
+    Damage,
+    Heal
+}
+
+// Who caused a health change, and with which swing. `attack_id` is unique per attack started
+// by a `CombatManager`, so multiple hits from the same swing can be told apart from new swings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthModifySource {
+    pub attacker: Entity,
+    pub attack_id: u32
+}
+
+#[derive(Event, Debug)]
 pub struct HealthModifyEvent {
-    pub amount: i32,
-    pub damaged_entity: Entity
+    // Always positive, `kind` decides whether it is added or subtracted.
+    pub amount: f32,
+    pub kind: HealthModifyKind,
+    pub target_entity: Entity,
+    pub source: Option<HealthModifySource>
 }
 
-#[derive(Event)]
-pub struct DeathEvent(Entity);
+impl HealthModifyEvent {
+    pub fn damage(target_entity: Entity, amount: f32, source: Option<HealthModifySource>) -> Self {
+        Self {
+            amount,
+            kind: HealthModifyKind::Damage,
+            target_entity,
+            source
+        }
+    }
+
+    pub fn heal(target_entity: Entity, amount: f32, source: Option<HealthModifySource>) -> Self {
+        Self {
+            amount,
+            kind: HealthModifyKind::Heal,
+            target_entity,
+            source
+        }
+    }
+
+    pub fn signed_amount(&self) -> f32 {
+        match self.kind {
+            HealthModifyKind::Damage => -self.amount,
+            HealthModifyKind::Heal => self.amount
+        }
+    }
+}
+
+#[derive(Event, Debug)]
+pub struct DeathEvent {
+    pub entity: Entity,
+    // The source of the killing blow, if any.
+    pub source: Option<HealthModifySource>
+}
 
 pub fn setup() {
     //Not sure
@@ -65,20 +125,33 @@ pub fn setup() {
 pub fn health_modify(
     mut health_modify_event: EventReader<HealthModifyEvent>,
     mut death_event_writer: EventWriter<DeathEvent>,
-    mut health_query: Query<&mut Health>
+    mut health_query: Query<(&mut Health, Option<&DamageImmune>)>
 ) {
     //receive event and do things based on event.
     for event in health_modify_event.read() {
-        println!("Health Modify Event: {:?}", event.amount);
-        let Ok(mut health) = health_query.get_mut(event.damaged_entity) else {
-            println!("No health component found for entity: {}", event.damaged_entity);
+        debug!("Health Modify Event: {:?} {} on {:?} from {:?}", event.kind, event.amount, event.target_entity, event.source);
+        let Ok((mut health, damage_immune)) = health_query.get_mut(event.target_entity) else {
+            debug!("No health component found for entity: {}", event.target_entity);
             continue;
         };
 
-        health.current_health = (health.current_health + event.amount).clamp(0, health.max_health);
+        // Already dead, the despawn just hasn't happened yet.
+        if health.current_health <= 0. {
+            continue;
+        }
 
-        if health.current_health <= 0 {
-            death_event_writer.send(DeathEvent(event.damaged_entity));
+        if damage_immune.is_some() && event.kind == HealthModifyKind::Damage {
+            debug!("Entity {:?} is immune to damage", event.target_entity);
+            continue;
+        }
+
+        health.current_health = (health.current_health + event.signed_amount()).clamp(0., health.max_health);
+
+        if health.current_health <= 0. {
+            death_event_writer.send(DeathEvent {
+                entity: event.target_entity,
+                source: event.source
+            });
         }
     }
 }
@@ -89,7 +162,9 @@ pub fn die(
 ) {
     for event in death_event.read() {
 
-        let Some(entity) = commands.get_entity(event.0) else {
+        info!("Entity {:?} died, killed by {:?}", event.entity, event.source);
+
+        let Some(entity) = commands.get_entity(event.entity) else {
             continue;
         };
         
@@ -1,4 +1,6 @@
-use asset_loader::{AssetLoadingState, CharacterHandle, DogmanGltf, EnemyGltf, EnemyHandle};
+use asset_loader::{AnimatedModelSources, AnimatedModels, AssetLoadingState};
+use std::collections::HashMap;
+
 use bevy::{gltf::GltfNode, prelude::*, scene::ron::de};
 use avian3d::prelude::*;
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
@@ -58,11 +60,13 @@ fn describe_dogman(
     
     scene: Res<Assets<Scene>>,
     gltf: Res<Assets<Gltf>>,
-    dogman_resource: Option<Res<EnemyHandle>>,
-    dogman_gltf_resource: Option<Res<EnemyGltf>>,
+    animated_models: Option<Res<AnimatedModels>>,
+    model_sources: Option<Res<AnimatedModelSources>>,
     mut dogman_next_state: ResMut<NextState<DescribedDogman>>
 ) {
-    
+    let dogman_resource = animated_models.as_ref().and_then(|animated_models| animated_models.get("AlienEnemy.glb"));
+    let dogman_gltf_resource = model_sources.as_ref().and_then(|model_sources| model_sources.0.iter().find(|source| source.id == "AlienEnemy.glb"));
+
     match dogman_resource {
         None => println!("Dogman resource not found"),
         Some(this_resource) => {
@@ -118,8 +122,36 @@ fn get_nodes_in_scene(
 }
 
 
+// Every `AnimationPlayer` in the scene hierarchy of an animated entity. The character's own
+// player comes first, attachments with their own skeleton (weapons, capes) follow it.
+#[derive(Component, Debug)]
+pub struct AnimationEntityLink(pub Vec<LinkedAnimationPlayer>);
+
+impl AnimationEntityLink {
+    // The player animating the entity's own model, the one bones and notifies are read from.
+    // `None` while only attachments are linked.
+    pub fn main(&self) -> Option<Entity> {
+        self.0
+            .iter()
+            .find(|linked_player| linked_player.model.is_none())
+            .map(|linked_player| linked_player.entity)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkedAnimationPlayer {
+    pub entity: Entity,
+    // The attachment's model, `None` for the entity's own model.
+    pub model: Option<String>,
+}
+
+// Marks the root of a scene attached to an animated entity, e.g. a weapon. Animation players
+// under it play the same states with the clips of `model`, which gets loaded when this is added
+// and doesn't need a state machine of its own.
 #[derive(Component, Debug)]
-pub struct AnimationEntityLink(pub Entity);
+pub struct AnimatedAttachment {
+    pub model: String,
+}
 
 //Pinkponk's cool code: https://github.com/bevyengine/bevy/discussions/5564#discussioncomment-3333257
 fn get_top_parent(mut curr_entity: Entity, parent_query: &Query<&Parent>) -> Entity {
@@ -137,21 +169,33 @@ fn get_top_parent(mut curr_entity: Entity, parent_query: &Query<&Parent>) -> Ent
 pub fn link_animations(
     player_query: Query<Entity, Added<AnimationPlayer>>,
     parent_query: Query<&Parent>,
+    attachment_query: Query<&AnimatedAttachment>,
     animations_entity_link_query: Query<&AnimationEntityLink>,
     mut commands: Commands,
 ) {
+    let mut links: HashMap<Entity, Vec<LinkedAnimationPlayer>> = HashMap::new();
+
     // Get all the Animation players which can be deep and hidden in the heirachy
     for entity in player_query.iter() {
         let top_entity = get_top_parent(entity, &parent_query);
 
-        // If the top parent has an animation config ref then link the player to the config
-        if animations_entity_link_query.get(top_entity).is_ok() {
-            warn!("Problem with multiple animationsplayers for the same top parent");
-        } else {
-            println!("Top Entity is : {:?}",    top_entity);
-            commands
-                .entity(top_entity)
-                .insert(AnimationEntityLink(entity.clone()));
-        }
+        let model = std::iter::once(entity)
+            .chain(parent_query.iter_ancestors(entity))
+            .find_map(|ancestor| attachment_query.get(ancestor).ok())
+            .map(|attachment| attachment.model.clone());
+
+        links
+            .entry(top_entity)
+            .or_insert_with(|| animations_entity_link_query.get(top_entity).map_or(Vec::new(), |link| link.0.clone()))
+            .push(LinkedAnimationPlayer { entity, model });
     }
-}
\ No newline at end of file
+
+    for (top_entity, mut linked_players) in links {
+        // Keep the entity's own player first.
+        linked_players.sort_by_key(|linked_player| linked_player.model.is_some());
+
+        commands
+            .entity(top_entity)
+            .insert(AnimationEntityLink(linked_players));
+    }
+}
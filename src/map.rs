@@ -8,12 +8,16 @@ pub fn plugin(app: &mut App) {
         .add_systems(OnEnter(AssetLoadingState::Loaded), setup);
 }
 
+#[derive(Component)]
+pub struct Map;
+
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     map_assets: Res<MapHandle>
 ) {
     let id = commands.spawn((
+        Map,
         SceneRoot(map_assets.scene.clone()), 
         Transform::from_xyz(0.0, 0.0, 0.0),
         RigidBody::Static,